@@ -1,139 +1,2715 @@
+extern crate parity_wasm;
+extern crate pwasm_utils;
 extern crate wabt;
+extern crate wasmabi;
 extern crate wasmi;
 
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 
-use wasmi::{ImportResolver, ModuleInstance, NopExternals, RuntimeValue};
+use wasmabi::{
+    Descriptor, ErrorCode, ItemKind, LogLevel, ABI_VERSION, ABI_VERSION_GLOBAL, ALLOC_EXPORT,
+    FREE_EXPORT, MAX_PANIC_MESSAGE, PID_GLOBAL, PRAGMA_BREAKPOINT, PRAGMA_MARKER, STDERR, STDOUT,
+    TYPE_BUFFER, TYPE_F32, TYPE_F64, TYPE_I32, TYPE_I64, TYPE_NONE,
+};
+use wasmi::ValueType::{I32, I64};
+use wasmi::{ImportResolver, ModuleInstance, RuntimeValue};
 
-struct Imports {}
+/// Handle of the root process. `_spawn` never hands this out (its handles always have counter bits
+/// set), so nothing can collide with it.
+const ROOT_PROCESS: u32 = 0b0010;
+
+/// Host function indices at and above this are bindings made by `_bind`, not syscalls.
+const BINDING_BASE: usize = 1 << 16;
+
+/// What `pwasm_utils::inject_gas_counter` calls the function it makes every block call to pay for
+/// itself.
+const GAS_IMPORT: &str = "gas";
+/// Its index in `SYSCALLS`.
+const GAS_INDEX: usize = 18;
+
+/// Instructions a task gets to run before it has to give another one a turn.
+const FUEL_PER_SLICE: i64 = 10_000;
+
+/// The most a memory the host makes for a process can grow to, in 64KiB pages.
+const MAX_MEMORY_PAGES: u32 = 256;
+
+/// The most elements a table the host makes for a process can grow to.
+const MAX_TABLE_ELEMENTS: u32 = 4096;
+
+/// Largest module `_create` will accept, in bytes.
+const MAX_MODULE_SIZE: u32 = 16 * 1024 * 1024;
+
+/// The most calls into processes (by `_invoke`, bindings and so on) that can be nested inside each
+/// other at once. Every one of them takes up some of the host's own stack.
+const MAX_CALL_DEPTH: u32 = 128;
+
+/// The most values a process's own code can have on its stack at once, as counted by
+/// `pwasm_utils::stack_height`. It's well under what wasmi allows, so it's what gets hit first.
+const MAX_VALUE_STACK: u32 = 64 * 1024;
+
+/// A host function: its name, the names and types of its parameters (the names are just for
+/// tracing), what it returns, and its index in `invoke_index`.
+///
+/// Syscalls that return an i32 return a status, unless they have a parameter called `error`. Then
+/// they return a value, and write the status there.
+type HostFunc = (
+    &'static str,
+    &'static [(&'static str, wasmi::ValueType)],
+    Option<wasmi::ValueType>,
+    usize,
+);
+
+/// The `env` syscalls every process gets, unless its parent bound something over the top.
+const SYSCALLS: &[HostFunc] = &[
+    ("_pragma", &[("code", I32), ("value", I32)], None, 1),
+    (
+        "_create",
+        &[("bytecode", I32), ("bytecode_length", I32), ("error", I32)],
+        Some(I32),
+        2,
+    ),
+    (
+        "_bind",
+        &[
+            ("handle", I32),
+            ("fn_name", I32),
+            ("fn_name_length", I32),
+            ("func", I32),
+        ],
+        Some(I32),
+        3,
+    ),
+    ("_spawn", &[("handle", I32), ("error", I32)], Some(I32), 4),
+    (
+        "_invoke",
+        &[
+            ("handle", I32),
+            ("fn_name", I32),
+            ("fn_name_length", I32),
+            ("arguments", I32),
+            ("argtypes", I32),
+            ("arglen", I32),
+            ("result", I32),
+            ("result_type", I32),
+            ("panic_message", I32),
+            ("panic_message_length", I32),
+        ],
+        Some(I32),
+        5,
+    ),
+    (
+        "_import_count",
+        &[("handle", I32), ("error", I32)],
+        Some(I32),
+        6,
+    ),
+    (
+        "_import_describe",
+        &[
+            ("handle", I32),
+            ("index", I32),
+            ("buf", I32),
+            ("buf_length", I32),
+            ("error", I32),
+        ],
+        Some(I32),
+        7,
+    ),
+    (
+        "_export_count",
+        &[("handle", I32), ("error", I32)],
+        Some(I32),
+        8,
+    ),
+    (
+        "_export_describe",
+        &[
+            ("handle", I32),
+            ("index", I32),
+            ("buf", I32),
+            ("buf_length", I32),
+            ("error", I32),
+        ],
+        Some(I32),
+        9,
+    ),
+    ("_kill", &[("handle", I32)], Some(I32), 10),
+    ("_close", &[("handle", I32)], Some(I32), 11),
+    (
+        "_bind_env",
+        &[
+            ("handle", I32),
+            ("fn_name", I32),
+            ("fn_name_length", I32),
+            ("func", I32),
+            ("env", I32),
+        ],
+        Some(I32),
+        12,
+    ),
+    (
+        "_bind_service",
+        &[
+            ("handle", I32),
+            ("fn_name", I32),
+            ("fn_name_length", I32),
+            ("service", I32),
+            ("service_length", I32),
+        ],
+        Some(I32),
+        13,
+    ),
+    (
+        "_bind_export",
+        &[
+            ("handle", I32),
+            ("fn_name", I32),
+            ("fn_name_length", I32),
+            ("target", I32),
+            ("export", I32),
+            ("export_length", I32),
+        ],
+        Some(I32),
+        14,
+    ),
+    (
+        "_read_memory",
+        &[("handle", I32), ("src", I32), ("len", I32), ("dst", I32)],
+        Some(I32),
+        17,
+    ),
+    (GAS_IMPORT, &[("amount", I32)], None, GAS_INDEX),
+    (
+        "_set_fuel",
+        &[("handle", I32), ("fuel", I64)],
+        Some(I32),
+        19,
+    ),
+    (
+        "_invoke_async",
+        &[
+            ("handle", I32),
+            ("fn_name", I32),
+            ("fn_name_length", I32),
+            ("arguments", I32),
+            ("argtypes", I32),
+            ("arglen", I32),
+            ("error", I32),
+        ],
+        Some(I32),
+        20,
+    ),
+    (
+        "_poll",
+        &[
+            ("job", I32),
+            ("result", I32),
+            ("result_type", I32),
+            ("panic_message", I32),
+            ("panic_message_length", I32),
+        ],
+        Some(I32),
+        21,
+    ),
+    (
+        "_wait",
+        &[
+            ("job", I32),
+            ("result", I32),
+            ("result_type", I32),
+            ("panic_message", I32),
+            ("panic_message_length", I32),
+        ],
+        Some(I32),
+        22,
+    ),
+    ("_cancel", &[("job", I32)], Some(I32), 23),
+    (
+        "_channel_create",
+        &[("capacity", I32), ("sender", I32), ("receiver", I32)],
+        Some(I32),
+        24,
+    ),
+    (
+        "_send",
+        &[
+            ("handle", I32),
+            ("bytes", I32),
+            ("bytes_length", I32),
+            ("handles", I32),
+            ("handles_length", I32),
+        ],
+        Some(I32),
+        25,
+    ),
+    (
+        "_recv",
+        &[
+            ("handle", I32),
+            ("buffer", I32),
+            ("buffer_length", I32),
+            ("length", I32),
+            ("handles", I32),
+            ("handles_capacity", I32),
+            ("handles_length", I32),
+        ],
+        Some(I32),
+        26,
+    ),
+    ("_give", &[("handle", I32), ("process", I32)], Some(I32), 27),
+    (
+        "_memory_create",
+        &[("pages", I32), ("max_pages", I32), ("error", I32)],
+        Some(I32),
+        28,
+    ),
+    (
+        "_bind_memory",
+        &[
+            ("handle", I32),
+            ("name", I32),
+            ("name_length", I32),
+            ("memory", I32),
+        ],
+        Some(I32),
+        29,
+    ),
+    (
+        "_memory_read",
+        &[("memory", I32), ("offset", I32), ("dst", I32), ("len", I32)],
+        Some(I32),
+        30,
+    ),
+    (
+        "_memory_write",
+        &[("memory", I32), ("offset", I32), ("src", I32), ("len", I32)],
+        Some(I32),
+        31,
+    ),
+    (
+        "_write",
+        &[("fd", I32), ("ptr", I32), ("len", I32)],
+        Some(I32),
+        32,
+    ),
+    (
+        "_set_limits",
+        &[
+            ("handle", I32),
+            ("memory_pages", I32),
+            ("table_elements", I32),
+            ("module_size", I32),
+            ("call_depth", I32),
+            ("value_stack", I32),
+        ],
+        Some(I32),
+        33,
+    ),
+    (
+        "_panic",
+        &[("message", I32), ("message_length", I32)],
+        None,
+        34,
+    ),
+];
+
+/// Things the host provides that a parent can bind a child's imports to with `_bind_service`.
+/// Unlike bindings, these run as whoever calls them.
+const SERVICES: &[HostFunc] = &[
+    // (ptr, len) of a UTF-8 string to print.
+    ("log", &[("ptr", I32), ("len", I32)], None, 15),
+    // Nanoseconds since the host started.
+    ("clock", &[], Some(I64), 16),
+];
+
+fn alloc_host_func(&(_, params, result, index): &HostFunc) -> wasmi::FuncRef {
+    let params: Vec<_> = params.iter().map(|&(_, ty)| ty).collect();
+
+    wasmi::FuncInstance::alloc_host(wasmi::Signature::new(params, result), index)
+}
+
+fn resolve_syscall(field_name: &str) -> Option<wasmi::FuncRef> {
+    SYSCALLS
+        .iter()
+        .find(|func| func.0 == field_name)
+        .map(alloc_host_func)
+}
+
+fn resolve_service(service_name: &str) -> Option<wasmi::FuncRef> {
+    SERVICES
+        .iter()
+        .find(|func| func.0 == service_name)
+        .map(alloc_host_func)
+}
+
+/// How much of the pragma log to show, from `WASMOS_PRAGMA`. It's a level name, or `off`. Defaults
+/// to `info`.
+fn pragma_level() -> Option<LogLevel> {
+    use LogLevel::*;
+
+    match std::env::var("WASMOS_PRAGMA").as_deref() {
+        Ok("off") => None,
+        Ok("error") => Some(Error),
+        Ok("warn") => Some(Warn),
+        Ok("debug") => Some(Debug),
+        Ok("trace") => Some(Trace),
+        _ => Some(Info),
+    }
+}
+
+/// Writes a line of JSON to its output for every syscall the processes it's watching make.
+struct Tracer {
+    /// `None` means every process.
+    pids: Option<Vec<u32>>,
+    out: Box<dyn std::io::Write>,
+}
+
+impl Tracer {
+    /// Set up from `WASMOS_TRACE`, which is `all` or a comma separated list of process handles to
+    /// trace (like `0x2,0x11`). Traces go to stderr, or the file `WASMOS_TRACE_FILE` names.
+    fn from_env() -> Option<Self> {
+        let pids = match std::env::var("WASMOS_TRACE").ok()?.as_str() {
+            "" => return None,
+            "all" => None,
+            list => Some(
+                list.split(',')
+                    .filter_map(|pid| {
+                        let pid = pid.trim();
+                        let parsed = match pid.strip_prefix("0x") {
+                            Some(hex) => u32::from_str_radix(hex, 16),
+                            None => pid.parse(),
+                        };
+
+                        if parsed.is_err() {
+                            eprintln!("wasmos: warning: can't trace {:?}, not a handle", pid);
+                        }
+
+                        parsed.ok()
+                    })
+                    .collect(),
+            ),
+        };
+
+        let out: Box<dyn std::io::Write> = match std::env::var_os("WASMOS_TRACE_FILE") {
+            Some(path) => match std::fs::File::create(&path) {
+                Ok(file) => Box::new(std::io::LineWriter::new(file)),
+                Err(e) => {
+                    eprintln!(
+                        "wasmos: warning: can't write trace to {}: {}",
+                        std::path::Path::new(&path).display(),
+                        e
+                    );
+                    Box::new(std::io::stderr())
+                }
+            },
+            None => Box::new(std::io::stderr()),
+        };
+
+        Some(Tracer { pids, out })
+    }
+
+    fn traces(&self, pid: u32) -> bool {
+        self.pids.as_ref().is_none_or(|pids| pids.contains(&pid))
+    }
+
+    /// Records one finished syscall. `time` is when it ran, relative to when the host started. The
+    /// pid is written in hex, like `WASMOS_TRACE` and the pragma log have it.
+    fn record(
+        &mut self,
+        pid: u32,
+        func: &HostFunc,
+        args: &[RuntimeValue],
+        result: &Result<Option<RuntimeValue>, wasmi::Trap>,
+        status: Option<u32>,
+        time: std::ops::Range<std::time::Duration>,
+    ) {
+        use std::io::Write;
+
+        let &(name, params, _, _) = func;
+
+        let args = params
+            .iter()
+            .zip(args)
+            .map(|(&(param, _), &arg)| format!("\"{}\":{}", param, json_value(arg)))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let mut outcome = match result {
+            Ok(None) => "\"result\":null".to_string(),
+            Ok(Some(value)) => format!("\"result\":{}", json_value(*value)),
+            Err(e) => format!("\"trap\":{}", json_string(&e.to_string())),
+        };
+
+        if let Some(code) = status.filter(|&code| code != 0) {
+            let error = match ErrorCode::from_u32(code) {
+                Some(error) => json_string(&format!("{:?}", error)),
+                None => code.to_string(),
+            };
+            outcome.push_str(&format!(",\"error\":{}", error));
+        }
+
+        // It's only a trace. If it can't be written there's nothing better to do with it.
+        let _ = writeln!(
+            self.out,
+            "{{\"time\":{:.6},\"pid\":\"{:#x}\",\"syscall\":{},\"args\":{{{}}},{},\"duration\":{:.6}}}",
+            time.start.as_secs_f64(),
+            pid,
+            json_string(name),
+            args,
+            outcome,
+            (time.end - time.start).as_secs_f64()
+        );
+    }
+}
+
+fn json_value(value: RuntimeValue) -> String {
+    // JSON has no NaN or infinities, so those go in as strings.
+    let float = |v: f64| {
+        if v.is_finite() {
+            v.to_string()
+        } else {
+            json_string(&v.to_string())
+        }
+    };
+
+    match value {
+        // Almost all of these are pointers, lengths and handles, so unsigned reads better.
+        RuntimeValue::I32(v) => (v as u32).to_string(),
+        RuntimeValue::I64(v) => v.to_string(),
+        RuntimeValue::F32(v) => float(f64::from(v.to_float())),
+        RuntimeValue::F64(v) => float(v.to_float()),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+/// The `env` globals every process can import. `pid` is the importing process.
+fn resolve_global(
+    field_name: &str,
+    descriptor: &wasmi::GlobalDescriptor,
+    pid: u32,
+) -> Option<wasmi::GlobalRef> {
+    let value = match field_name {
+        PID_GLOBAL => pid,
+        ABI_VERSION_GLOBAL => ABI_VERSION,
+        _ => return None,
+    };
+
+    // They're constants, so they're no use to anything that wants to write to them.
+    if descriptor.is_mutable() {
+        return None;
+    }
+
+    Some(wasmi::GlobalInstance::alloc(
+        RuntimeValue::I32(value as i32),
+        false,
+    ))
+}
+
+/// How far something that starts out `initial` big can grow, given the `maximum` it asked for
+/// (if any) and the limit of the process it's for. Fails if it starts out too big already.
+fn capped(initial: u32, maximum: Option<u32>, host_max: u32, limit: u32) -> Result<u32, ErrorCode> {
+    if initial > host_max {
+        return Err(ErrorCode::TooLarge);
+    }
+
+    if initial > limit {
+        return Err(ErrorCode::LimitExceeded);
+    }
+
+    Ok(maximum.unwrap_or(limit).min(limit))
+}
+
+/// A fresh memory for a process that imports one nobody provided. It can't grow past the
+/// process's limit, even if the process says it can.
+fn alloc_memory(
+    descriptor: &wasmi::MemoryDescriptor,
+    limits: &Limits,
+) -> Result<wasmi::MemoryRef, ErrorCode> {
+    use wasmi::memory_units::Pages;
+
+    let maximum = capped(
+        descriptor.initial(),
+        descriptor.maximum(),
+        MAX_MEMORY_PAGES,
+        limits.memory_pages,
+    )?;
+
+    wasmi::MemoryInstance::alloc(
+        Pages(descriptor.initial() as usize),
+        Some(Pages(maximum as usize)),
+    )
+    .map_err(|_| ErrorCode::AllocationFailed)
+}
+
+/// A fresh table for a process that imports one. Same deal as `alloc_memory`.
+fn alloc_table(
+    descriptor: &wasmi::TableDescriptor,
+    limits: &Limits,
+) -> Result<wasmi::TableRef, ErrorCode> {
+    let maximum = capped(
+        descriptor.initial(),
+        descriptor.maximum(),
+        MAX_TABLE_ELEMENTS,
+        limits.table_elements,
+    )?;
+
+    wasmi::TableInstance::alloc(descriptor.initial(), Some(maximum))
+        .map_err(|_| ErrorCode::AllocationFailed)
+}
+
+/// Caps how far the module's own memory and table can grow at the limits of the process it's
+/// for, the same way `alloc_memory` and `alloc_table` do for imported ones.
+fn cap_module(
+    module: &mut parity_wasm::elements::Module,
+    limits: &Limits,
+) -> Result<(), ErrorCode> {
+    use parity_wasm::elements::{MemoryType, TableType};
+
+    if let Some(section) = module.memory_section_mut() {
+        for mem in section.entries_mut() {
+            let initial = mem.limits().initial();
+            let maximum = capped(
+                initial,
+                mem.limits().maximum(),
+                MAX_MEMORY_PAGES,
+                limits.memory_pages,
+            )?;
+            *mem = MemoryType::new(initial, Some(maximum));
+        }
+    }
+
+    if let Some(section) = module.table_section_mut() {
+        for table in section.entries_mut() {
+            let initial = table.limits().initial();
+            let maximum = capped(
+                initial,
+                table.limits().maximum(),
+                MAX_TABLE_ELEMENTS,
+                limits.table_elements,
+            )?;
+            *table = TableType::new(initial, Some(maximum));
+        }
+    }
+
+    Ok(())
+}
+
+/// Caps the module's memory and table with `cap_module`, and how deep its stack can get. `pid` is
+/// the process it's for.
+///
+/// Also returns the index of the global its stack height is counted in.
+fn limit_module(
+    mut module: parity_wasm::elements::Module,
+    limits: &Limits,
+    pid: u32,
+) -> Result<(wasmi::Module, usize), ErrorCode> {
+    cap_module(&mut module, limits)?;
+    inline_global_imports(&mut module, pid)?;
+
+    // `inject_limiter` adds its global to the end.
+    let stack_height = module
+        .global_section()
+        .map_or(0, |section| section.entries().len());
+    let module = pwasm_utils::stack_height::inject_limiter(module, limits.value_stack)
+        .map_err(|_| ErrorCode::UnsupportedFeature)?;
+
+    let module =
+        wasmi::Module::from_parity_wasm_module(module).map_err(|_| ErrorCode::ValidationFailed)?;
+
+    Ok((module, stack_height))
+}
+
+/// Swaps the module's global imports for plain globals holding what they'd be resolved to. They're
+/// constants anyway, and `inject_limiter` gets the index of its global wrong in modules that import
+/// any.
+fn inline_global_imports(
+    module: &mut parity_wasm::elements::Module,
+    pid: u32,
+) -> Result<(), ErrorCode> {
+    use parity_wasm::elements::{
+        External, GlobalEntry, GlobalSection, InitExpr, Instruction, Section, ValueType,
+    };
+
+    let imports = match module.import_section_mut() {
+        Some(section) => section.entries_mut(),
+        None => return Ok(()),
+    };
+
+    let mut values = Vec::new();
+    for import in imports.iter() {
+        if let External::Global(ty) = import.external() {
+            let value = match (import.module(), import.field()) {
+                ("env", PID_GLOBAL) => pid,
+                ("env", ABI_VERSION_GLOBAL) => ABI_VERSION,
+                _ => return Err(ErrorCode::MissingImport),
+            };
+
+            // Same as `resolve_global`.
+            if ty.is_mutable() {
+                return Err(ErrorCode::MissingImport);
+            }
+
+            if ty.content_type() != ValueType::I32 {
+                return Err(ErrorCode::InstantiationFailed);
+            }
+
+            values.push((*ty, value as i32));
+        }
+    }
+
+    if values.is_empty() {
+        return Ok(());
+    }
+
+    imports.retain(|import| !matches!(import.external(), External::Global(_)));
+
+    let constant = |value| InitExpr::new(vec![Instruction::I32Const(value), Instruction::End]);
+
+    // Initializers can only get imported globals, so anything that did needs the value instead.
+    let inline = |expr: &mut InitExpr| {
+        if let [Instruction::GetGlobal(idx), Instruction::End] = *expr.code() {
+            *expr = constant(values[idx as usize].1);
+        }
+    };
+
+    if let Some(section) = module.global_section_mut() {
+        section
+            .entries_mut()
+            .iter_mut()
+            .for_each(|global| inline(global.init_expr_mut()));
+    }
+
+    if let Some(section) = module.data_section_mut() {
+        section
+            .entries_mut()
+            .iter_mut()
+            .filter_map(|segment| segment.offset_mut().as_mut())
+            .for_each(inline);
+    }
+
+    if let Some(section) = module.elements_section_mut() {
+        section
+            .entries_mut()
+            .iter_mut()
+            .filter_map(|segment| segment.offset_mut().as_mut())
+            .for_each(inline);
+    }
+
+    // Imported globals come first, so putting these first keeps all the indices the same.
+    let entries = values
+        .iter()
+        .map(|&(ty, value)| GlobalEntry::new(ty, constant(value)));
+
+    match module.global_section_mut() {
+        Some(section) => {
+            section.entries_mut().splice(0..0, entries);
+        }
+        None => module
+            .sections_mut()
+            .push(Section::Global(GlobalSection::with_entries(
+                entries.collect(),
+            ))),
+    }
+
+    Ok(())
+}
+
+/// What the root process gets to import. Remembers the memory and table it was given, since it
+/// might not export them.
+#[derive(Default)]
+struct Imports {
+    memory: RefCell<Option<wasmi::MemoryRef>>,
+    table: RefCell<Option<wasmi::TableRef>>,
+}
+
+fn not_found(module_name: &str, field_name: &str) -> wasmi::Error {
+    wasmi::Error::Instantiation(format!(
+        "could not find {} in module {}",
+        field_name, module_name
+    ))
+}
 
 impl ImportResolver for Imports {
     fn resolve_func(
         &self,
         module_name: &str,
         field_name: &str,
-        signature: &wasmi::Signature,
+        _signature: &wasmi::Signature,
     ) -> std::result::Result<wasmi::FuncRef, wasmi::Error> {
-        dbg!(module_name, field_name, signature);
+        if module_name == "env" {
+            if let Some(func) = resolve_syscall(field_name) {
+                return Ok(func);
+            }
+        }
+
+        Err(not_found(module_name, field_name))
+    }
+
+    fn resolve_global(
+        &self,
+        module_name: &str,
+        field_name: &str,
+        descriptor: &wasmi::GlobalDescriptor,
+    ) -> std::result::Result<wasmi::GlobalRef, wasmi::Error> {
+        if module_name == "env" {
+            if let Some(global) = resolve_global(field_name, descriptor, ROOT_PROCESS) {
+                return Ok(global);
+            }
+        }
+
+        Err(not_found(module_name, field_name))
+    }
+
+    fn resolve_memory(
+        &self,
+        module_name: &str,
+        field_name: &str,
+        descriptor: &wasmi::MemoryDescriptor,
+    ) -> std::result::Result<wasmi::MemoryRef, wasmi::Error> {
+        if module_name != "env" {
+            return Err(not_found(module_name, field_name));
+        }
+
+        let mem = alloc_memory(descriptor, &HOST_LIMITS).map_err(|e| {
+            wasmi::Error::Instantiation(format!("couldn't allocate {}: {:?}", field_name, e))
+        })?;
+        *self.memory.borrow_mut() = Some(mem.clone());
+
+        Ok(mem)
+    }
+
+    fn resolve_table(
+        &self,
+        module_name: &str,
+        field_name: &str,
+        descriptor: &wasmi::TableDescriptor,
+    ) -> std::result::Result<wasmi::TableRef, wasmi::Error> {
+        if module_name != "env" {
+            return Err(not_found(module_name, field_name));
+        }
+
+        let table = alloc_table(descriptor, &HOST_LIMITS).map_err(|e| {
+            wasmi::Error::Instantiation(format!("couldn't allocate {}: {:?}", field_name, e))
+        })?;
+        *self.table.borrow_mut() = Some(table.clone());
+
+        Ok(table)
+    }
+}
+
+struct HostExternals {
+    processes: HashMap<u32, Process>,
+    spawned_processes: HashMap<u32, SpawnedProcess>,
+    bindings: HashMap<usize, Binding>,
+    next_binding: usize,
+    /// The process at the top is the one that's running. Syscalls are made on its behalf.
+    call_stack: Vec<u32>,
+    new_idx: u32,
+    /// For the clock service, and timestamps in the pragma log.
+    started: std::time::Instant,
+    /// The most detailed pragmas get logged at, if any.
+    pragma_level: Option<LogLevel>,
+    tracer: Option<Tracer>,
+    /// Function calls into spawned processes. They get run in turns, `FUEL_PER_SLICE` at a time.
+    tasks: HashMap<u32, Task>,
+    run_queue: VecDeque<u32>,
+    slice: Slice,
+    channels: HashMap<u32, Channel>,
+    next_channel: u32,
+    /// Handles to the ends of channels.
+    endpoints: HashMap<u32, Endpoint>,
+    /// Memories that can be bound to more than one process.
+    shared_memories: HashMap<u32, SharedMemory>,
+}
+
+impl HostExternals {
+    fn new(module: wasmi::ModuleRef, imports: Imports) -> Self {
+        // Nobody owns the root process, so nobody can get a handle to it.
+        let mut root = SpawnedProcess::new(module, 0);
+        let Imports { memory, table } = imports;
+        root.mem = root.mem.or_else(|| memory.into_inner());
+        root.table = root.table.or_else(|| table.into_inner());
+
+        let mut spawned_processes = HashMap::new();
+        spawned_processes.insert(ROOT_PROCESS, root);
+
+        HostExternals {
+            new_idx: 0,
+            processes: Default::default(),
+            spawned_processes,
+            bindings: Default::default(),
+            next_binding: 0,
+            call_stack: vec![ROOT_PROCESS],
+            started: std::time::Instant::now(),
+            pragma_level: pragma_level(),
+            tracer: Tracer::from_env(),
+            tasks: Default::default(),
+            run_queue: Default::default(),
+            slice: Slice { fuel: 0, depth: 0 },
+            channels: Default::default(),
+            next_channel: 0,
+            endpoints: Default::default(),
+            shared_memories: Default::default(),
+        }
+    }
+
+    /// How many process handles are alive, not counting the root process.
+    fn live_handles(&self) -> usize {
+        self.processes.len() + self.spawned_processes.len() - 1
+    }
+
+    /// The process making the current syscall.
+    fn caller(&self) -> u32 {
+        *self.call_stack.last().unwrap()
+    }
+
+    /// The limits of the process making the current syscall.
+    fn limits(&self) -> Limits {
+        self.spawned_processes[&self.caller()].limits
+    }
+
+    /// Puts a process on the call stack to run some of its code, unless that would nest calls
+    /// deeper than the host or the process allow.
+    fn enter(&mut self, pid: u32) -> Result<(), wasmi::Trap> {
+        let sp = &self.spawned_processes[&pid];
+        let nested = self.call_stack.iter().filter(|&&p| p == pid).count();
+
+        if self.call_stack.len() >= MAX_CALL_DEPTH as usize
+            || nested >= sp.limits.call_depth as usize
+        {
+            return Err(trap(ErrorCode::StackOverflow));
+        }
+
+        // Nothing else of its own is running, so anything left on its stack height is from calls
+        // that trapped before they could take it off again.
+        let running = nested > 0
+            || self
+                .tasks
+                .values()
+                .any(|task| task.process == pid && task.started && task.result.is_none());
+        if let Some(height) = sp.stack_height.as_ref().filter(|_| !running) {
+            height
+                .set(RuntimeValue::I32(0))
+                .expect("stack height should be a mutable i32");
+        }
+
+        self.call_stack.push(pid);
+
+        Ok(())
+    }
+
+    /// Works out whether a trap out of a process's code was because it went over its value stack
+    /// limit. `inject_limiter` makes that an `unreachable`, so it looks like any other.
+    fn overflowed(&self, pid: u32, trap: wasmi::Trap) -> wasmi::Trap {
+        let over_limit = self.spawned_processes.get(&pid).and_then(|sp| {
+            let height: u32 = sp.stack_height.as_ref()?.get().try_into()?;
+            Some(height > sp.limits.value_stack)
+        });
+
+        match trap.kind() {
+            wasmi::TrapKind::StackOverflow => self::trap(ErrorCode::StackOverflow),
+            wasmi::TrapKind::Unreachable if over_limit == Some(true) => {
+                self::trap(ErrorCode::StackOverflow)
+            }
+            _ => trap,
+        }
+    }
+
+    /// Runs `f` with `pid` on the call stack. See `enter`.
+    fn run_as<T>(
+        &mut self,
+        pid: u32,
+        f: impl FnOnce(&mut Self) -> Result<T, wasmi::Trap>,
+    ) -> Result<T, wasmi::Trap> {
+        self.enter(pid)?;
+        let result = f(self);
+        self.call_stack.pop();
+
+        result.map_err(|e| self.overflowed(pid, e))
+    }
+
+    fn mem(&self) -> Result<&wasmi::MemoryRef, ErrorCode> {
+        self.spawned_processes[&self.caller()]
+            .mem
+            .as_ref()
+            .ok_or(ErrorCode::OutOfBounds)
+    }
+
+    fn read_bytes(&self, ptr: u32, len: u32) -> Result<Vec<u8>, ErrorCode> {
+        self.mem()?
+            .get(ptr, len as usize)
+            .map_err(|_| ErrorCode::OutOfBounds)
+    }
+
+    fn read_string(&self, ptr: u32, len: u32) -> Result<String, ErrorCode> {
+        String::from_utf8(self.read_bytes(ptr, len)?).map_err(|_| ErrorCode::InvalidUtf8)
+    }
+
+    /// Writes an error code out to a result pointer. A null pointer means the caller doesn't care.
+    fn write_result(&self, ptr: u32, result: Result<(), ErrorCode>) -> Result<(), wasmi::Trap> {
+        if ptr == 0 {
+            return Ok(());
+        }
+
+        self.mem()
+            .ok()
+            .and_then(|mem| mem.set_value(ptr, status(result)).ok())
+            .ok_or_else(|| wasmi::Trap::new(wasmi::TrapKind::MemoryAccessOutOfBounds))
+    }
+
+    /// For syscalls that return a value and write their error code out separately. The value is 0
+    /// on failure.
+    fn returning(
+        &self,
+        result_ptr: u32,
+        result: Result<u32, ErrorCode>,
+    ) -> Result<Option<wasmi::RuntimeValue>, wasmi::Trap> {
+        self.write_result(result_ptr, result.map(|_| ()))?;
+
+        Ok(Some(result.unwrap_or(0).into()))
+    }
+
+    /// A created (not yet spawned) process, if the caller owns it.
+    fn process(&self, handle: u32) -> Result<&Process, ErrorCode> {
+        let caller = self.caller();
+
+        self.processes
+            .get(&handle)
+            .filter(|proc| proc.owner == caller)
+            .ok_or(ErrorCode::InvalidHandle)
+    }
+
+    fn create(&mut self, bytecode_ptr: u32, bytecode_length: u32) -> Result<u32, ErrorCode> {
+        let limits = self.limits();
+
+        if bytecode_length > MAX_MODULE_SIZE {
+            return Err(ErrorCode::TooLarge);
+        }
+
+        if bytecode_length > limits.module_size {
+            return Err(ErrorCode::LimitExceeded);
+        }
+
+        let bytecode = self.read_bytes(bytecode_ptr, bytecode_length)?;
+
+        let (module, imports, exports) = load_module(&bytecode)?;
+
+        self.new_idx += 16;
+        let idx = self.new_idx | 0b0001;
+
+        let proc = Process {
+            module,
+            bindings: Default::default(),
+            owner: self.caller(),
+            imports,
+            exports,
+            limits,
+        };
+
+        self.processes.insert(idx, proc);
+
+        Ok(idx)
+    }
+
+    /// `_bind` and `_bind_env`. With an environment, the function takes it as an extra first
+    /// argument, which the child doesn't see.
+    fn bind(&mut self, args: &wasmi::RuntimeArgs, with_env: bool) -> Result<(), ErrorCode> {
+        let handle: u32 = args.nth(0);
+        let fn_name_ptr: u32 = args.nth(1);
+        let fn_name_length: u32 = args.nth(2);
+        let fnptr: u32 = args.nth(3);
+        let env: Option<u32> = if with_env { Some(args.nth(4)) } else { None };
+
+        let fn_name_str = self.read_string(fn_name_ptr, fn_name_length)?;
+
+        let caller = self.caller();
+
+        // If you passed a *pointer* to memory rather than a table index, this is where you end
+        // up.
+        let fnref = self.spawned_processes[&caller]
+            .table
+            .as_ref()
+            .and_then(|table| table.get(fnptr).ok().flatten())
+            .ok_or(ErrorCode::InvalidFunction)?;
+
+        let signature = match env {
+            Some(_) => {
+                let params = fnref.signature().params();
+
+                if params.first() != Some(&wasmi::ValueType::I32) {
+                    return Err(ErrorCode::InvalidFunction);
+                }
+
+                wasmi::Signature::new(params[1..].to_vec(), fnref.signature().return_type())
+            }
+            None => fnref.signature().clone(),
+        };
+
+        self.add_binding(
+            handle,
+            fn_name_str,
+            signature,
+            Binding {
+                owner: caller,
+                func: fnref,
+                env,
+            },
+        )
+    }
+
+    /// `_bind_service`. Binds an import straight to a host service.
+    fn bind_service(&mut self, args: &wasmi::RuntimeArgs) -> Result<(), ErrorCode> {
+        let handle: u32 = args.nth(0);
+        let fn_name_ptr: u32 = args.nth(1);
+        let fn_name_length: u32 = args.nth(2);
+        let service_ptr: u32 = args.nth(3);
+        let service_length: u32 = args.nth(4);
+
+        let fn_name_str = self.read_string(fn_name_ptr, fn_name_length)?;
+        let service_str = self.read_string(service_ptr, service_length)?;
+
+        let service = resolve_service(&service_str).ok_or(ErrorCode::NoSuchService)?;
+
+        let caller = self.caller();
+        let proc = self
+            .processes
+            .get_mut(&handle)
+            .filter(|proc| proc.owner == caller)
+            .ok_or(ErrorCode::InvalidHandle)?;
+
+        proc.bindings.bindings.insert(fn_name_str, service);
+
+        Ok(())
+    }
+
+    /// `_bind_export`. Binds an import to an exported function of another process the caller
+    /// spawned, which runs as that process.
+    fn bind_export(&mut self, args: &wasmi::RuntimeArgs) -> Result<(), ErrorCode> {
+        let handle: u32 = args.nth(0);
+        let fn_name_ptr: u32 = args.nth(1);
+        let fn_name_length: u32 = args.nth(2);
+        let target: u32 = args.nth(3);
+        let export_ptr: u32 = args.nth(4);
+        let export_length: u32 = args.nth(5);
+
+        let fn_name_str = self.read_string(fn_name_ptr, fn_name_length)?;
+        let export_str = self.read_string(export_ptr, export_length)?;
+
+        let caller = self.caller();
+
+        let func = self
+            .spawned_processes
+            .get(&target)
+            .filter(|sp| sp.owner == caller)
+            .ok_or(ErrorCode::InvalidHandle)?
+            .module
+            .export_by_name(&export_str)
+            .ok_or(ErrorCode::NoSuchExport)?
+            .as_func()
+            .cloned()
+            .ok_or(ErrorCode::NotAFunction)?;
+
+        let signature = func.signature().clone();
+
+        self.add_binding(
+            handle,
+            fn_name_str,
+            signature,
+            Binding {
+                owner: target,
+                func,
+                env: None,
+            },
+        )
+    }
+
+    fn add_binding(
+        &mut self,
+        handle: u32,
+        name: String,
+        signature: wasmi::Signature,
+        binding: Binding,
+    ) -> Result<(), ErrorCode> {
+        let caller = self.caller();
+        let proc = self
+            .processes
+            .get_mut(&handle)
+            .filter(|proc| proc.owner == caller)
+            .ok_or(ErrorCode::InvalidHandle)?;
+
+        // The child doesn't get the function itself, it gets a trampoline that switches to the
+        // binding's owner before calling it. Otherwise syscalls made by the function would look
+        // like they came from the child.
+        let id = self.next_binding;
+        self.next_binding += 1;
+
+        let trampoline = wasmi::FuncInstance::alloc_host(signature, BINDING_BASE + id);
+
+        self.bindings.insert(id, binding);
+
+        proc.bindings.bindings.insert(name, trampoline);
+        proc.bindings.ids.push(id);
+
+        Ok(())
+    }
+
+    /// Writes out the descriptor of an import or export of a created process, if it fits in the
+    /// buffer. Returns how long the descriptor is either way, so the caller can try again with a
+    /// bigger buffer.
+    fn describe(&self, args: &wasmi::RuntimeArgs, exports: bool) -> Result<u32, ErrorCode> {
+        let handle: u32 = args.nth(0);
+        let item_index: u32 = args.nth(1);
+        let buf_ptr: u32 = args.nth(2);
+        let buf_len: u32 = args.nth(3);
+
+        let desc = self
+            .items(handle, exports)?
+            .get(item_index as usize)
+            .ok_or(ErrorCode::NoSuchItem)?
+            .descriptor();
+
+        let len = desc.encoded_len();
+
+        if len <= buf_len as usize {
+            let mut buf = vec![0; len];
+            desc.encode(&mut buf);
+
+            self.mem()?
+                .set(buf_ptr, &buf)
+                .map_err(|_| ErrorCode::OutOfBounds)?;
+        }
+
+        Ok(len as u32)
+    }
+
+    /// The imports or exports of a created or spawned process, if the caller owns it.
+    fn items(&self, handle: u32, exports: bool) -> Result<&[Item], ErrorCode> {
+        let caller = self.caller();
+
+        let (imports, exported) = match self.process(handle) {
+            Ok(proc) => (&proc.imports, &proc.exports),
+            Err(_) => {
+                let sp = self
+                    .spawned_processes
+                    .get(&handle)
+                    .filter(|sp| sp.owner == caller)
+                    .ok_or(ErrorCode::InvalidHandle)?;
+
+                (&sp.imports, &sp.exports)
+            }
+        };
+
+        Ok(if exports { exported } else { imports })
+    }
+
+    fn spawn(&mut self, handle: u32) -> Result<u32, ErrorCode> {
+        let caller = self.caller();
+
+        self.process(handle)?;
+        let mut proc = self.processes.remove(&handle).unwrap();
+
+        self.new_idx += 16;
+        let idx = self.new_idx | 0b0010;
+        proc.bindings.limits = proc.limits;
+
+        let imports = wasmi::ImportsBuilder::default().with_resolver("env", &proc.bindings);
+
+        let (module, stack_height) = match limit_module(proc.module.clone(), &proc.limits, idx) {
+            Ok(module) => module,
+            Err(e) => {
+                self.release_bindings(&proc.bindings.ids);
+                return Err(e);
+            }
+        };
+
+        let not_started = match ModuleInstance::new(&module, &imports) {
+            Ok(not_started) => not_started,
+            Err(e) => {
+                self.release_bindings(&proc.bindings.ids);
+
+                return Err(e
+                    .as_host_error()
+                    .and_then(|e| e.downcast_ref::<SyscallError>())
+                    .map(|e| e.0)
+                    .unwrap_or(ErrorCode::InstantiationFailed));
+            }
+        };
+
+        // The start function might make syscalls, so the process needs to exist before it runs.
+        let mut sp = SpawnedProcess::new(not_started.not_started_instance().clone(), caller);
+        // A process that imports its memory or table doesn't have to export it too.
+        sp.mem = sp.mem.or_else(|| proc.bindings.memory.take());
+        sp.table = sp.table.or_else(|| proc.bindings.table.take());
+        sp.bindings = proc.bindings.ids.clone();
+        sp.imports = proc.imports;
+        sp.exports = proc.exports;
+        sp.limits = proc.limits;
+        sp.stack_height = sp.module.globals().get(stack_height).cloned();
+        self.spawned_processes.insert(idx, sp);
+
+        let started = self.run_as(idx, |this| not_started.run_start(this));
+
+        if let Err(e) = started {
+            self.reap(&[idx]);
+
+            return Err(match syscall_error(&e) {
+                Some(ErrorCode::StackOverflow) => ErrorCode::StackOverflow,
+                _ => ErrorCode::InstantiationFailed,
+            });
+        }
+
+        Ok(idx)
+    }
+
+    fn release_bindings(&mut self, ids: &[usize]) {
+        for id in ids {
+            self.bindings.remove(id);
+        }
+    }
+
+    /// Throws away the created process behind a handle, along with anything bound into it.
+    fn close(&mut self, handle: u32) -> Result<(), ErrorCode> {
+        if self.endpoint(handle).is_ok() {
+            self.close_endpoint(handle);
+            return Ok(());
+        }
+
+        if self.shared_memory(handle).is_ok() {
+            self.shared_memories.remove(&handle);
+            return Ok(());
+        }
+
+        self.process(handle)?;
+        let proc = self.processes.remove(&handle).unwrap();
+
+        self.release_bindings(&proc.bindings.ids);
+
+        Ok(())
+    }
+
+    /// Kills a spawned process, and everything it spawned or created.
+    fn kill(&mut self, handle: u32) -> Result<(), ErrorCode> {
+        let caller = self.caller();
+
+        self.spawned_processes
+            .get(&handle)
+            .filter(|sp| sp.owner == caller)
+            .ok_or(ErrorCode::InvalidHandle)?;
+
+        let mut doomed = vec![handle];
+        let mut i = 0;
+        while i < doomed.len() {
+            let parent = doomed[i];
+            doomed.extend(
+                self.spawned_processes
+                    .iter()
+                    .filter(|(_, sp)| sp.owner == parent)
+                    .map(|(&handle, _)| handle),
+            );
+            i += 1;
+        }
+
+        // We'd be pulling the rug out from under whatever is running in there.
+        if doomed.iter().any(|handle| self.call_stack.contains(handle)) {
+            return Err(ErrorCode::ProcessBusy);
+        }
+
+        self.reap(&doomed);
+
+        Ok(())
+    }
+
+    /// Removes spawned processes, along with their bindings and the processes they created but
+    /// never spawned. Bindings to their exports stop working, and tasks waiting to run in them
+    /// are cancelled.
+    fn reap(&mut self, handles: &[u32]) {
+        // Nobody's left to collect these.
+        self.tasks.retain(|_, task| !handles.contains(&task.owner));
+
+        for task in self.tasks.values_mut() {
+            if task.result.is_none() && handles.contains(&task.process) {
+                task.result = Some(Err(trap(ErrorCode::InvalidHandle)));
+            }
+        }
+
+        let tasks = &self.tasks;
+        self.run_queue
+            .retain(|id| tasks.get(id).is_some_and(|task| task.result.is_none()));
+
+        for handle in handles {
+            let sp = self.spawned_processes.remove(handle).unwrap();
+            self.release_bindings(&sp.bindings);
+
+            self.bindings.retain(|_, binding| binding.owner != *handle);
+
+            let endpoints: Vec<u32> = self
+                .endpoints
+                .iter()
+                .filter(|(_, ep)| ep.owner == *handle)
+                .map(|(&handle, _)| handle)
+                .collect();
+
+            for endpoint in endpoints {
+                self.close_endpoint(endpoint);
+            }
+
+            // Anything it was bound to keeps its own reference.
+            self.shared_memories
+                .retain(|_, shared| shared.owner != *handle);
+
+            let orphans: Vec<u32> = self
+                .processes
+                .iter()
+                .filter(|(_, proc)| proc.owner == *handle)
+                .map(|(&handle, _)| handle)
+                .collect();
+
+            for orphan in orphans {
+                let proc = self.processes.remove(&orphan).unwrap();
+                self.release_bindings(&proc.bindings.ids);
+            }
+        }
+    }
+
+    fn invoke(&mut self, args: &wasmi::RuntimeArgs) -> Result<(), ErrorCode> {
+        let ptrs = ResultPtrs::from_args(args, 6);
+
+        let task = self.schedule_call(args)?;
+        let result = self.run_until(task);
+        // If it couldn't be run, it mustn't be left to run later.
+        self.tasks.remove(&task);
+        self.run_queue.retain(|&id| id != task);
+
+        self.write_returned(result, ptrs)
+    }
+
+    /// Checks the arguments to `_invoke` or `_invoke_async` against the function they're for, then
+    /// queues up the call. Returns the task's ID.
+    fn schedule_call(&mut self, args: &wasmi::RuntimeArgs) -> Result<u32, ErrorCode> {
+        let handle: u32 = args.nth(0);
+        let fn_name_ptr: u32 = args.nth(1);
+        let fn_name_length: u32 = args.nth(2);
+        let arg_ptr: u32 = args.nth(3);
+        let arg_ty_ptr: u32 = args.nth(4);
+        let arg_len: u32 = args.nth(5);
+
+        let fn_name_str = self.read_string(fn_name_ptr, fn_name_length)?;
+
+        let caller = self.caller();
+
+        let module = self
+            .spawned_processes
+            .get(&handle)
+            .filter(|sp| sp.owner == caller)
+            .ok_or(ErrorCode::InvalidHandle)?
+            .module
+            .clone();
+
+        let exp = module
+            .export_by_name(&fn_name_str)
+            .ok_or(ErrorCode::NoSuchExport)?;
+        let func = exp.as_func().ok_or(ErrorCode::NotAFunction)?.clone();
+
+        let mem = self.mem()?.clone();
+
+        let mut idx = arg_ptr;
+
+        let arg_types = self.read_bytes(arg_ty_ptr, arg_len)?;
+
+        // What the function would be getting, where a buffer is two i32s.
+        let mut provided = Vec::new();
+        for &ty in &arg_types {
+            use wasmi::ValueType::*;
+
+            match ty {
+                TYPE_I32 => provided.push(I32),
+                TYPE_I64 => provided.push(I64),
+                TYPE_F32 => provided.push(F32),
+                TYPE_F64 => provided.push(F64),
+                TYPE_BUFFER => provided.extend_from_slice(&[I32, I32]),
+                _ => return Err(ErrorCode::TypeMismatch),
+            }
+        }
+
+        let params = func.signature().params();
+        if provided.len() != params.len() {
+            return Err(ErrorCode::ArityMismatch);
+        }
+        if provided != params {
+            return Err(ErrorCode::TypeMismatch);
+        }
+
+        let mut runtime_values = Vec::<wasmi::RuntimeValue>::new();
+        // Buffers get copied over once we've read all the arguments, so a bad one doesn't leave
+        // anything allocated in the callee. If copying one over fails, the ones before it are
+        // given back.
+        let mut buffers = Vec::new();
+
+        for ty in arg_types {
+            use wasmi::nan_preserving_float::{F32, F64};
+
+            if ty == TYPE_BUFFER {
+                let ptr: u32 = mem.get_value(idx).map_err(|_| ErrorCode::OutOfBounds)?;
+                let len: u32 = mem.get_value(idx + 4).map_err(|_| ErrorCode::OutOfBounds)?;
+
+                buffers.push((runtime_values.len(), self.read_bytes(ptr, len)?));
+                // The pointer gets filled in later.
+                runtime_values.push(0.into());
+                runtime_values.push(len.into());
+
+                idx += 8;
+                continue;
+            }
+
+            let rtv = match ty {
+                TYPE_I32 => mem.get_value::<i32>(idx).map(Into::into),
+                TYPE_I64 => mem.get_value::<i64>(idx).map(Into::into),
+                TYPE_F32 => mem.get_value::<F32>(idx).map(Into::into),
+                TYPE_F64 => mem.get_value::<F64>(idx).map(Into::into),
+                _ => unreachable!("checked the types above"),
+            }
+            .map_err(|_| ErrorCode::OutOfBounds)?;
+
+            // yes this means we have padding bytes for 32 bit types
+            // i do not care.
+            idx += 8;
+
+            runtime_values.push(rtv);
+        }
+
+        let mut copied = Vec::new();
+        for (i, bytes) in buffers {
+            match self.copy_in(handle, &bytes) {
+                Ok(ptr) => {
+                    runtime_values[i] = ptr.into();
+                    copied.push((ptr, bytes.len() as u32));
+                }
+                Err(e) => {
+                    self.free_in(handle, &copied);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(self.schedule(handle, func, runtime_values))
+    }
+
+    /// Writes what a finished task returned into the caller's memory, and its type too. If it
+    /// panicked, what it said goes out instead.
+    fn write_returned(
+        &self,
+        result: Result<Option<wasmi::RuntimeValue>, wasmi::Trap>,
+        ptrs: ResultPtrs,
+    ) -> Result<(), ErrorCode> {
+        let result = match result {
+            Ok(result) => result,
+            Err(trap) => {
+                if let Some(message) = panic_message(&trap) {
+                    self.write_panic(message, ptrs)?;
+                }
+
+                return Err(trap_code(&trap));
+            }
+        };
+
+        let mem = self.mem()?;
+        let (result_ptr, result_ty_ptr) = (ptrs.value, ptrs.ty);
+
+        // Always all 8 bytes, so the caller never sees leftovers from before.
+        let (ty, bits) = {
+            use wasmi::RuntimeValue::*;
+            match result {
+                None => (TYPE_NONE, 0),
+                Some(I32(v)) => (TYPE_I32, v as u32 as u64),
+                Some(I64(v)) => (TYPE_I64, v as u64),
+                Some(F32(v)) => (TYPE_F32, v.to_bits() as u64),
+                Some(F64(v)) => (TYPE_F64, v.to_bits()),
+            }
+        };
+
+        if result_ptr != 0 {
+            mem.set_value(result_ptr, bits as i64)
+                .map_err(|_| ErrorCode::OutOfBounds)?;
+        }
+        if result_ty_ptr != 0 {
+            mem.set(result_ty_ptr, &[ty])
+                .map_err(|_| ErrorCode::OutOfBounds)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes as much of a panic message as fits into the caller's buffer, and how long it is into
+    /// where the result would have gone.
+    fn write_panic(&self, message: &str, ptrs: ResultPtrs) -> Result<(), ErrorCode> {
+        let mem = self.mem()?;
+
+        let len = message.len().min(ptrs.panic_message_length as usize);
+        if len != 0 {
+            mem.set(ptrs.panic_message, &message.as_bytes()[..len])
+                .map_err(|_| ErrorCode::OutOfBounds)?;
+        }
+        if ptrs.value != 0 {
+            mem.set_value(ptrs.value, message.len() as i64)
+                .map_err(|_| ErrorCode::OutOfBounds)?;
+        }
+
+        Ok(())
+    }
+
+    /// Queues up a call to a function in a spawned process, on behalf of the caller. Returns the
+    /// task's ID.
+    fn schedule(
+        &mut self,
+        process: u32,
+        func: wasmi::FuncRef,
+        args: Vec<wasmi::RuntimeValue>,
+    ) -> u32 {
+        let invocation = wasmi::FuncInstance::invoke_resumable(&func, args)
+            .expect("arguments should have been checked against the signature already");
+
+        self.new_idx += 16;
+        let id = self.new_idx | 0b0100;
+
+        self.tasks.insert(
+            id,
+            Task {
+                process,
+                owner: self.caller(),
+                invocation: Some(invocation),
+                started: false,
+                result: None,
+            },
+        );
+        self.run_queue.push_back(id);
+
+        id
+    }
+
+    /// Runs tasks in turns until the given one is done, then returns what it returned.
+    fn run_until(&mut self, id: u32) -> Result<Option<wasmi::RuntimeValue>, wasmi::Trap> {
+        loop {
+            let task = self
+                .tasks
+                .get(&id)
+                .ok_or_else(|| trap(ErrorCode::InvalidHandle))?;
+
+            if task.result.is_some() {
+                return self.tasks.remove(&id).unwrap().result.unwrap();
+            }
+
+            // If we're waiting on it from inside itself, it'll never finish.
+            if task.invocation.is_none() {
+                return Err(trap(ErrorCode::ProcessBusy));
+            }
+
+            // Same if everything queued is waiting on a call that's further up the stack.
+            let next = self
+                .next_task(Some(id))
+                .ok_or_else(|| trap(ErrorCode::ProcessBusy))?;
+
+            if let Err(trap) = self.run_slice(next) {
+                if next == id {
+                    self.tasks.remove(&id);
+                    return Err(trap);
+                }
+
+                self.run_queue.push_back(next);
+            }
+        }
+    }
+
+    /// Takes the next task off the queue that can run now. Processes expect their calls to nest
+    /// rather than take turns, so a task can't start while another one in the same process has
+    /// been preempted partway through, or while the process is further up the call stack. Unless
+    /// that's where we're waiting on it from, in which case it finishes before anything up there
+    /// carries on.
+    fn next_task(&mut self, waiting_on: Option<u32>) -> Option<u32> {
+        let tasks = &self.tasks;
+        let call_stack = &self.call_stack;
+        let busy = |id: u32, process: u32| {
+            let preempted = tasks
+                .values()
+                .any(|task| task.process == process && task.started && task.invocation.is_some());
+
+            preempted || (waiting_on != Some(id) && call_stack.contains(&process))
+        };
+
+        let index = self.run_queue.iter().position(|&id| {
+            let task = &tasks[&id];
+            task.started || !busy(id, task.process)
+        })?;
+
+        self.run_queue.remove(index)
+    }
+
+    /// Runs a task until it finishes or uses up its slice, in which case it goes to the back of
+    /// the queue. Fails without running it if we're nested too deep to right now, and leaves it up
+    /// to the caller what to do with it.
+    fn run_slice(&mut self, id: u32) -> Result<(), wasmi::Trap> {
+        let process = self.tasks[&id].process;
+        self.enter(process)?;
+
+        let task = self.tasks.get_mut(&id).unwrap();
+        let started = std::mem::replace(&mut task.started, true);
+        // Taken out while it runs, so anyone looking can tell it's running.
+        let mut invocation = task.invocation.take().unwrap();
+
+        let outer = std::mem::replace(
+            &mut self.slice,
+            Slice {
+                fuel: FUEL_PER_SLICE,
+                depth: self.call_stack.len(),
+            },
+        );
+
+        let result = if started {
+            invocation.resume_execution(None, self)
+        } else {
+            invocation.start_execution(self)
+        };
+
+        self.slice = outer;
+        self.call_stack.pop();
+
+        let task = self.tasks.get_mut(&id).unwrap();
+        match result {
+            Err(wasmi::ResumableError::Trap(trap)) if is_preempted(&trap) => {
+                task.invocation = Some(invocation);
+                self.run_queue.push_back(id);
+            }
+            Err(wasmi::ResumableError::Trap(trap)) => {
+                let trap = self.overflowed(process, trap);
+                self.tasks.get_mut(&id).unwrap().result = Some(Err(trap));
+            }
+            // It was in no state to be started or resumed. That's no fault of the process's, so
+            // only the call fails.
+            Err(_) => task.result = Some(Err(trap(ErrorCode::HostError))),
+            Ok(value) => task.result = Some(Ok(value)),
+        }
+
+        Ok(())
+    }
+
+    /// The caller's job with the given handle.
+    fn job(&self, job: u32) -> Result<&Task, ErrorCode> {
+        let caller = self.caller();
+
+        self.tasks
+            .get(&job)
+            .filter(|task| task.owner == caller)
+            .ok_or(ErrorCode::InvalidHandle)
+    }
+
+    /// Gives one queued task a turn, then collects the job's result if it's done.
+    fn poll(&mut self, job: u32, ptrs: ResultPtrs) -> Result<(), ErrorCode> {
+        if self.job(job)?.result.is_none() {
+            if let Some(next) = self.next_task(None) {
+                if self.run_slice(next).is_err() {
+                    self.run_queue.push_back(next);
+                }
+            }
+        }
+
+        if self.job(job)?.result.is_none() {
+            return Err(ErrorCode::Pending);
+        }
+
+        let result = self.tasks.remove(&job).unwrap().result.unwrap();
+        self.write_returned(result, ptrs)
+    }
+
+    /// Runs tasks until the job is done, then collects its result.
+    fn wait(&mut self, job: u32, ptrs: ResultPtrs) -> Result<(), ErrorCode> {
+        self.job(job)?;
+
+        let result = self.run_until(job);
+        self.write_returned(result, ptrs)
+    }
+
+    /// Throws away a job, whether or not it's done. The function it was running won't get any
+    /// further.
+    fn cancel(&mut self, job: u32) -> Result<(), ErrorCode> {
+        let task = self.job(job)?;
+        if task.result.is_none() && task.invocation.is_none() {
+            return Err(ErrorCode::ProcessBusy);
+        }
+
+        self.tasks.remove(&job);
+        self.run_queue.retain(|&id| id != job);
+
+        Ok(())
+    }
+
+    /// Pays for instructions the running process is about to run. This is what injected gas
+    /// calls end up at.
+    fn gas(&mut self, cost: u32) -> Result<(), wasmi::Trap> {
+        let cost = i64::from(cost);
+        let caller = self.caller();
+
+        if let Some(fuel) = &mut self.spawned_processes.get_mut(&caller).unwrap().fuel {
+            if *fuel < cost {
+                *fuel = 0;
+                return Err(trap(ErrorCode::OutOfFuel));
+            }
+
+            *fuel -= cost;
+        }
+
+        self.slice.fuel -= cost;
+
+        // Only the task's own code can be suspended. Anything it's called into (a syscall, or a
+        // function bound into it) has to finish first, so it'll get stopped once it's back.
+        if self.slice.fuel <= 0 && self.call_stack.len() == self.slice.depth {
+            return Err(wasmi::Trap::new(wasmi::TrapKind::Host(Box::new(Preempted))));
+        }
+
+        Ok(())
+    }
+
+    /// `_set_limits`. A process can't give one it created more than it has itself.
+    fn set_limits(&mut self, args: &wasmi::RuntimeArgs) -> Result<(), ErrorCode> {
+        let handle: u32 = args.nth(0);
+        let limits = Limits {
+            memory_pages: args.nth(1),
+            table_elements: args.nth(2),
+            module_size: args.nth(3),
+            call_depth: args.nth(4),
+            value_stack: args.nth(5),
+        };
+
+        let own = self.limits();
+        self.process(handle)?;
+
+        if limits.memory_pages > own.memory_pages
+            || limits.table_elements > own.table_elements
+            || limits.module_size > own.module_size
+            || limits.call_depth > own.call_depth
+            || limits.value_stack > own.value_stack
+        {
+            return Err(ErrorCode::LimitExceeded);
+        }
+
+        self.processes.get_mut(&handle).unwrap().limits = limits;
+
+        Ok(())
+    }
+
+    /// `_set_fuel`. Negative means no limit.
+    fn set_fuel(&mut self, handle: u32, fuel: i64) -> Result<(), ErrorCode> {
+        let caller = self.caller();
+
+        let sp = self
+            .spawned_processes
+            .get_mut(&handle)
+            .filter(|sp| sp.owner == caller)
+            .ok_or(ErrorCode::InvalidHandle)?;
+
+        sp.fuel = if fuel < 0 { None } else { Some(fuel) };
+
+        Ok(())
+    }
+
+    /// `_panic`. Makes the trap that ends the call, carrying the message out to whoever gets the
+    /// call's result. The message doesn't have to be valid UTF-8, or even in bounds.
+    fn panic(&self, ptr: u32, len: u32) -> wasmi::Trap {
+        let bytes = self
+            .read_bytes(ptr, len.min(MAX_PANIC_MESSAGE))
+            .unwrap_or_default();
+        let message = String::from_utf8_lossy(&bytes).into_owned();
+
+        wasmi::Trap::new(wasmi::TrapKind::Host(Box::new(Panicked(message))))
+    }
+
+    /// `_channel_create`. Both ends belong to the caller to start with.
+    fn channel_create(
+        &mut self,
+        capacity: u32,
+        sender_ptr: u32,
+        receiver_ptr: u32,
+    ) -> Result<(), ErrorCode> {
+        let mem = self.mem()?.clone();
+
+        let sender = (self.new_idx + 16) | 0b1000;
+        let receiver = (self.new_idx + 32) | 0b1000;
+
+        // Before anything exists, so a bad pointer doesn't leave a channel nobody can close.
+        mem.set_value(sender_ptr, sender)
+            .and_then(|_| mem.set_value(receiver_ptr, receiver))
+            .map_err(|_| ErrorCode::OutOfBounds)?;
+
+        self.new_idx += 32;
+        self.next_channel += 1;
+        let channel = self.next_channel;
+
+        self.channels.insert(
+            channel,
+            Channel {
+                queue: VecDeque::new(),
+                capacity: capacity as usize,
+                sender_open: true,
+                receiver_open: true,
+            },
+        );
+
+        let owner = self.caller();
+        for &(handle, sender) in &[(sender, true), (receiver, false)] {
+            self.endpoints.insert(
+                handle,
+                Endpoint {
+                    channel,
+                    owner,
+                    sender,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The end of a channel with the given handle, if the caller owns it.
+    fn endpoint(&self, handle: u32) -> Result<&Endpoint, ErrorCode> {
+        let caller = self.caller();
+
+        self.endpoints
+            .get(&handle)
+            .filter(|ep| ep.owner == caller)
+            .ok_or(ErrorCode::InvalidHandle)
+    }
+
+    /// `_send`. Handles sent along with the message belong to nobody until it's received.
+    fn send(&mut self, args: &wasmi::RuntimeArgs) -> Result<(), ErrorCode> {
+        let handle: u32 = args.nth(0);
+        let ptr: u32 = args.nth(1);
+        let len: u32 = args.nth(2);
+        let handles_ptr: u32 = args.nth(3);
+        let handles_len: u32 = args.nth(4);
+
+        let ep = self.endpoint(handle)?;
+        if !ep.sender {
+            return Err(ErrorCode::InvalidHandle);
+        }
+        let channel_id = ep.channel;
+
+        let channel = &self.channels[&channel_id];
+        if !channel.receiver_open {
+            return Err(ErrorCode::ChannelClosed);
+        }
+        if channel.queue.len() >= channel.capacity {
+            return Err(ErrorCode::ChannelFull);
+        }
+
+        let bytes = self.read_bytes(ptr, len)?;
+        let handles: Vec<u32> = self
+            .read_bytes(handles_ptr, handles_len.saturating_mul(4))?
+            .chunks(4)
+            .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect();
+
+        for (i, handle) in handles.iter().enumerate() {
+            // A channel can't carry its own ends, nobody would ever be able to get them out.
+            if self.endpoint(*handle)?.channel == channel_id || handles[..i].contains(handle) {
+                return Err(ErrorCode::InvalidHandle);
+            }
+        }
+
+        for handle in &handles {
+            self.endpoints.get_mut(handle).unwrap().owner = 0;
+        }
+
+        self.channels
+            .get_mut(&channel_id)
+            .unwrap()
+            .queue
+            .push_back(Message { bytes, handles });
+
+        Ok(())
+    }
+
+    /// `_recv`. If the message doesn't fit, it stays where it is, and the caller gets told how
+    /// big it is so it can try again.
+    fn recv(&mut self, args: &wasmi::RuntimeArgs) -> Result<(), ErrorCode> {
+        let handle: u32 = args.nth(0);
+        let buf_ptr: u32 = args.nth(1);
+        let buf_len: u32 = args.nth(2);
+        let len_ptr: u32 = args.nth(3);
+        let handles_ptr: u32 = args.nth(4);
+        let handles_cap: u32 = args.nth(5);
+        let handles_len_ptr: u32 = args.nth(6);
+
+        let ep = self.endpoint(handle)?;
+        if ep.sender {
+            return Err(ErrorCode::InvalidHandle);
+        }
+        let channel_id = ep.channel;
+
+        let mem = self.mem()?.clone();
+        let channel = &self.channels[&channel_id];
+
+        let msg = match channel.queue.front() {
+            Some(msg) => msg,
+            None if channel.sender_open => return Err(ErrorCode::ChannelEmpty),
+            None => return Err(ErrorCode::ChannelClosed),
+        };
+
+        if len_ptr != 0 {
+            mem.set_value(len_ptr, msg.bytes.len() as u32)
+                .map_err(|_| ErrorCode::OutOfBounds)?;
+        }
+        if handles_len_ptr != 0 {
+            mem.set_value(handles_len_ptr, msg.handles.len() as u32)
+                .map_err(|_| ErrorCode::OutOfBounds)?;
+        }
+
+        if msg.bytes.len() > buf_len as usize || msg.handles.len() > handles_cap as usize {
+            return Err(ErrorCode::TooLarge);
+        }
+
+        mem.set(buf_ptr, &msg.bytes)
+            .map_err(|_| ErrorCode::OutOfBounds)?;
+        for (i, &handle) in msg.handles.iter().enumerate() {
+            mem.set_value(handles_ptr + i as u32 * 4, handle)
+                .map_err(|_| ErrorCode::OutOfBounds)?;
+        }
+
+        let caller = self.caller();
+        let msg = self
+            .channels
+            .get_mut(&channel_id)
+            .unwrap()
+            .queue
+            .pop_front()
+            .unwrap();
+        for handle in msg.handles {
+            self.endpoints.get_mut(&handle).unwrap().owner = caller;
+        }
+
+        Ok(())
+    }
+
+    /// `_give`. Hands the end of a channel over to a process we spawned. The handle stays the
+    /// same, so we can tell it what it is.
+    fn give(&mut self, handle: u32, process: u32) -> Result<(), ErrorCode> {
+        let caller = self.caller();
+
+        self.endpoint(handle)?;
+        self.spawned_processes
+            .get(&process)
+            .filter(|sp| sp.owner == caller)
+            .ok_or(ErrorCode::InvalidHandle)?;
+
+        self.endpoints.get_mut(&handle).unwrap().owner = process;
+
+        Ok(())
+    }
+
+    /// Gets rid of the end of a channel. Once the receiver's gone, nothing in the queue can be
+    /// received, so that goes (and any handles in it). Once both are gone, so is the channel.
+    fn close_endpoint(&mut self, handle: u32) {
+        let ep = self.endpoints.remove(&handle).unwrap();
+        let channel = self.channels.get_mut(&ep.channel).unwrap();
+
+        if ep.sender {
+            channel.sender_open = false;
+        } else {
+            channel.receiver_open = false;
+        }
+
+        let lost = if channel.receiver_open {
+            Vec::new()
+        } else {
+            channel.queue.drain(..).collect()
+        };
+
+        if !channel.sender_open && !channel.receiver_open {
+            self.channels.remove(&ep.channel);
+        }
+
+        for handle in lost.into_iter().flat_map(|msg| msg.handles) {
+            self.close_endpoint(handle);
+        }
+    }
+
+    /// `_pragma`. It's only a hint, so anything wrong with it (like a bad pointer) just means it
+    /// gets ignored.
+    fn pragma(&self, code: u32, value: u32) {
+        let (level, event) = match code {
+            PRAGMA_BREAKPOINT => (LogLevel::Debug, "breakpoint".to_string()),
+            PRAGMA_MARKER => match self.read_pragma_str(value) {
+                Some(name) => (LogLevel::Debug, format!("marker name={:?}", name)),
+                None => return,
+            },
+            _ => match (LogLevel::from_u32(code), self.read_pragma_str(value)) {
+                (Some(level), Some(msg)) => (level, format!("log msg={:?}", msg)),
+                _ => return,
+            },
+        };
+
+        if self.pragma_level.is_none_or(|max| level > max) {
+            return;
+        }
+
+        eprintln!(
+            "pragma time={:.6} pid={:#x} level={} {}",
+            self.started.elapsed().as_secs_f64(),
+            self.caller(),
+            level.name(),
+            event
+        );
+    }
+
+    /// The string a (ptr, len) pair at `ptr` points to.
+    fn read_pragma_str(&self, ptr: u32) -> Option<String> {
+        let mem = self.mem().ok()?;
+        let str_ptr: u32 = mem.get_value(ptr).ok()?;
+        let len: u32 = mem.get_value(ptr.checked_add(4)?).ok()?;
+
+        let bytes = self.read_bytes(str_ptr, len).ok()?;
+        Some(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// `_write`. Bytes go out as they are, they don't have to be UTF-8.
+    fn write(&self, fd: u32, ptr: u32, len: u32) -> Result<(), ErrorCode> {
+        use std::io::Write;
+
+        let bytes = self.read_bytes(ptr, len)?;
+
+        // Nothing useful a process could do about the host failing to write, so don't tell it.
+        let _ = match fd {
+            STDOUT => std::io::stdout().write_all(&bytes),
+            STDERR => std::io::stderr().write_all(&bytes),
+            _ => return Err(ErrorCode::InvalidHandle),
+        };
 
-        match module_name {
-            "env" => match field_name {
-                "_pragma" => {
-                    use wasmi::ValueType::*;
+        Ok(())
+    }
 
-                    return Ok(wasmi::FuncInstance::alloc_host(
-                        wasmi::Signature::new(&[I32, I32][..], None),
-                        1,
-                    ));
-                }
-                "_create" => {
-                    use wasmi::ValueType::*;
+    /// `_memory_create`. The maximum is capped at the caller's memory limit, which is also what a
+    /// negative one means.
+    fn memory_create(&mut self, pages: u32, max_pages: i32) -> Result<u32, ErrorCode> {
+        use wasmi::memory_units::Pages;
 
-                    return Ok(wasmi::FuncInstance::alloc_host(
-                        wasmi::Signature::new(&[I32, I32, I32][..], Some(I32)),
-                        2,
-                    ));
-                }
-                "_bind" => {
-                    use wasmi::ValueType::*;
+        let max_pages = if max_pages < 0 {
+            None
+        } else {
+            Some(max_pages as u32)
+        };
+        let max_pages = capped(
+            pages,
+            max_pages,
+            MAX_MEMORY_PAGES,
+            self.limits().memory_pages,
+        )?;
 
-                    return Ok(wasmi::FuncInstance::alloc_host(
-                        wasmi::Signature::new(&[I32, I32, I32, I32][..], Some(I32)),
-                        3,
-                    ));
-                }
-                "_spawn" => {
-                    use wasmi::ValueType::*;
+        let mem =
+            wasmi::MemoryInstance::alloc(Pages(pages as usize), Some(Pages(max_pages as usize)))
+                .map_err(|_| ErrorCode::AllocationFailed)?;
 
-                    return Ok(wasmi::FuncInstance::alloc_host(
-                        wasmi::Signature::new(&[I32][..], Some(I32)),
-                        4,
-                    ));
-                }
-                "_invoke" => {
-                    use wasmi::ValueType::*;
+        self.new_idx += 16;
+        let idx = self.new_idx | 0b1100;
 
-                    return Ok(wasmi::FuncInstance::alloc_host(
-                        wasmi::Signature::new(&[I32, I32, I32, I32, I32, I32, I32][..], Some(I32)),
-                        5,
-                    ));
-                }
-                _ => {}
+        self.shared_memories.insert(
+            idx,
+            SharedMemory {
+                mem,
+                owner: self.caller(),
             },
-            _ => {}
+        );
+
+        Ok(idx)
+    }
+
+    /// The shared memory with the given handle, if the caller owns it.
+    fn shared_memory(&self, handle: u32) -> Result<&SharedMemory, ErrorCode> {
+        let caller = self.caller();
+
+        self.shared_memories
+            .get(&handle)
+            .filter(|shared| shared.owner == caller)
+            .ok_or(ErrorCode::InvalidHandle)
+    }
+
+    /// `_bind_memory`. The child gets the memory itself, so it and everything else it's bound to
+    /// see the same bytes.
+    fn bind_memory(&mut self, args: &wasmi::RuntimeArgs) -> Result<(), ErrorCode> {
+        let handle: u32 = args.nth(0);
+        let name_ptr: u32 = args.nth(1);
+        let name_length: u32 = args.nth(2);
+        let memory: u32 = args.nth(3);
+
+        let name = self.read_string(name_ptr, name_length)?;
+        let mem = self.shared_memory(memory)?.mem.clone();
+
+        let caller = self.caller();
+        let proc = self
+            .processes
+            .get_mut(&handle)
+            .filter(|proc| proc.owner == caller)
+            .ok_or(ErrorCode::InvalidHandle)?;
+
+        proc.bindings.memories.insert(name, mem);
+
+        Ok(())
+    }
+
+    /// `_memory_read` and `_memory_write`, which copy between a shared memory and the caller's.
+    fn copy_shared(&mut self, args: &wasmi::RuntimeArgs, write: bool) -> Result<(), ErrorCode> {
+        let handle: u32 = args.nth(0);
+        let offset: u32 = args.nth(1);
+        let ptr: u32 = args.nth(2);
+        let len: u32 = args.nth(3);
+
+        let shared = &self.shared_memory(handle)?.mem;
+        let mem = self.mem()?;
+
+        let (from, from_offset, to, to_offset) = if write {
+            (mem, ptr, shared, offset)
+        } else {
+            (shared, offset, mem, ptr)
+        };
+
+        let bytes = from
+            .get(from_offset, len as usize)
+            .map_err(|_| ErrorCode::OutOfBounds)?;
+        to.set(to_offset, &bytes)
+            .map_err(|_| ErrorCode::OutOfBounds)
+    }
+
+    /// Copies bytes into a spawned process's memory, in space it allocates for them.
+    fn copy_in(&mut self, handle: u32, bytes: &[u8]) -> Result<u32, ErrorCode> {
+        let sp = &self.spawned_processes[&handle];
+        let module = sp.module.clone();
+        let mem = sp.mem.clone().ok_or(ErrorCode::AllocationFailed)?;
+
+        let ptr = self.run_as(handle, |this| {
+            module
+                .invoke_export(ALLOC_EXPORT, &[(bytes.len() as u32).into()], this)
+                .map_err(|e| match e {
+                    wasmi::Error::Trap(trap) => trap,
+                    // It doesn't export an allocator, or what it exports by that name isn't one.
+                    _ => self::trap(ErrorCode::AllocationFailed),
+                })
+        });
+
+        let ptr = match ptr {
+            Ok(Some(wasmi::RuntimeValue::I32(ptr))) if ptr != 0 => ptr as u32,
+            _ => return Err(ErrorCode::AllocationFailed),
+        };
+
+        if mem.set(ptr, bytes).is_err() {
+            self.free_in(handle, &[(ptr, bytes.len() as u32)]);
+            return Err(ErrorCode::OutOfBounds);
         }
 
-        Err(wasmi::Error::Instantiation(format!(
-            "could not find {} in module {}",
-            field_name, module_name
-        )))
+        Ok(ptr)
     }
 
-    fn resolve_global(
-        &self,
-        _module_name: &str,
-        _field_name: &str,
-        _descriptor: &wasmi::GlobalDescriptor,
-    ) -> std::result::Result<wasmi::GlobalRef, wasmi::Error> {
-        todo!()
+    /// Gives buffers from `copy_in` back to a spawned process, when the call they were for isn't
+    /// going to happen. If it doesn't export a way to free them, they stay where they are.
+    fn free_in(&mut self, handle: u32, buffers: &[(u32, u32)]) {
+        let module = self.spawned_processes[&handle].module.clone();
+
+        for &(ptr, len) in buffers {
+            let _ = self.run_as(handle, |this| {
+                let _ = module.invoke_export(FREE_EXPORT, &[ptr.into(), len.into()], this);
+                Ok(())
+            });
+        }
     }
 
-    fn resolve_memory(
-        &self,
-        _module_name: &str,
-        _field_name: &str,
-        _descriptor: &wasmi::MemoryDescriptor,
-    ) -> std::result::Result<wasmi::MemoryRef, wasmi::Error> {
-        todo!()
+    /// `_read_memory`. Copies bytes out of a spawned process's memory into the caller's.
+    fn read_memory(&self, args: &wasmi::RuntimeArgs) -> Result<(), ErrorCode> {
+        let handle: u32 = args.nth(0);
+        let src: u32 = args.nth(1);
+        let len: u32 = args.nth(2);
+        let dst: u32 = args.nth(3);
+
+        let caller = self.caller();
+
+        let bytes = self
+            .spawned_processes
+            .get(&handle)
+            .filter(|sp| sp.owner == caller)
+            .ok_or(ErrorCode::InvalidHandle)?
+            .mem
+            .as_ref()
+            .ok_or(ErrorCode::OutOfBounds)?
+            .get(src, len as usize)
+            .map_err(|_| ErrorCode::OutOfBounds)?;
+
+        self.mem()?
+            .set(dst, &bytes)
+            .map_err(|_| ErrorCode::OutOfBounds)
     }
 
-    fn resolve_table(
-        &self,
-        _module_name: &str,
-        _field_name: &str,
-        _descriptor: &wasmi::TableDescriptor,
-    ) -> std::result::Result<wasmi::TableRef, wasmi::Error> {
-        todo!()
+    /// Calls a bound function, as the process it belongs to.
+    fn call_binding(
+        &mut self,
+        index: usize,
+        args: wasmi::RuntimeArgs,
+    ) -> Result<Option<wasmi::RuntimeValue>, wasmi::Trap> {
+        // It's gone if the process it belonged to was killed.
+        let binding = self
+            .bindings
+            .get(&index)
+            .ok_or_else(|| trap(ErrorCode::InvalidHandle))?;
+        let owner = binding.owner;
+        let func = binding.func.clone();
+
+        let mut full_args = Vec::with_capacity(args.len() + 1);
+        if let Some(env) = binding.env {
+            full_args.push(env.into());
+        }
+        full_args.extend_from_slice(args.as_ref());
+
+        self.run_as(owner, |this| {
+            wasmi::FuncInstance::invoke(&func, &full_args, this)
+        })
     }
 }
 
-struct HostExternals {
-    processes: HashMap<u32, Process>,
-    spawned_processes: HashMap<u32, SpawnedProcess>,
-    new_idx: u32,
-    module: wasmi::ModuleRef,
-    mem: wasmi::MemoryRef,
+/// Decodes and validates bytecode, sorting out *why* it was rejected if it was. Also returns the
+/// module's imports and exports.
+fn load_module(
+    bytecode: &[u8],
+) -> Result<(parity_wasm::elements::Module, Vec<Item>, Vec<Item>), ErrorCode> {
+    use parity_wasm::elements::Error::*;
+
+    // Anything that isn't the binary format gets a go as the text format. wabt only parses it,
+    // validating is left to the same checks binary modules go through so both report it the same.
+    let converted;
+    let bytecode = if bytecode.starts_with(b"\0asm") {
+        bytecode
+    } else {
+        converted = wabt::Wat2Wasm::new()
+            .validate(false)
+            .convert(bytecode)
+            .map_err(|_| ErrorCode::Malformed)?;
+        converted.as_ref()
+    };
+
+    let module: parity_wasm::elements::Module =
+        parity_wasm::deserialize_buffer(bytecode).map_err(|e| match e {
+            // Things that are (or could be) valid wasm, just not wasm that we understand.
+            UnsupportedVersion(_)
+            | UnknownOpcode(_)
+            | UnknownValueType(_)
+            | UnknownTableElementType(_)
+            | InvalidSegmentFlags(_) => ErrorCode::UnsupportedFeature,
+            _ => ErrorCode::Malformed,
+        })?;
+
+    wasmi::Module::from_parity_wasm_module(module.clone())
+        .map_err(|_| ErrorCode::ValidationFailed)?;
+
+    // Only safe to do after validation, these trust all the indices in the module.
+    let (imports, exports) = module_items(&module);
+
+    let metered = pwasm_utils::inject_gas_counter(module, &Default::default())
+        .map_err(|_| ErrorCode::UnsupportedFeature)?;
+    wasmi::Module::from_parity_wasm_module(metered.clone())
+        .map_err(|_| ErrorCode::ValidationFailed)?;
+
+    Ok((metered, imports, exports))
 }
 
-impl HostExternals {
-    fn new(module: wasmi::ModuleRef) -> Self {
-        HostExternals {
-            new_idx: 0,
-            processes: Default::default(),
-            spawned_processes: Default::default(),
-            mem: module
-                .export_by_name("memory")
-                .unwrap()
-                .as_memory()
-                .unwrap()
-                .clone(),
-            module,
+fn type_tag(ty: parity_wasm::elements::ValueType) -> u8 {
+    use parity_wasm::elements::ValueType::*;
+
+    match ty {
+        I32 => TYPE_I32,
+        I64 => TYPE_I64,
+        F32 => TYPE_F32,
+        F64 => TYPE_F64,
+    }
+}
+
+fn module_items(module: &parity_wasm::elements::Module) -> (Vec<Item>, Vec<Item>) {
+    use parity_wasm::elements::{External, Internal, Type};
+
+    let types = module.type_section().map(|s| s.types()).unwrap_or(&[]);
+    let signature = |type_idx: u32| {
+        let Type::Function(ty) = &types[type_idx as usize];
+
+        (
+            ty.params().iter().cloned().map(type_tag).collect(),
+            ty.return_type().map(type_tag).unwrap_or(TYPE_NONE),
+        )
+    };
+
+    let import_entries = module.import_section().map(|s| s.entries()).unwrap_or(&[]);
+
+    // Imported functions and globals come first in their index spaces, then the module's own.
+    let mut function_types = Vec::new();
+    let mut global_types = Vec::new();
+
+    let mut imports = Vec::new();
+    for entry in import_entries {
+        let (kind, (params, result)) = match entry.external() {
+            External::Function(ty) => {
+                function_types.push(*ty);
+                (ItemKind::Function, signature(*ty))
+            }
+            External::Table(_) => (ItemKind::Table, (vec![], TYPE_NONE)),
+            External::Memory(_) => (ItemKind::Memory, (vec![], TYPE_NONE)),
+            External::Global(global) => {
+                global_types.push(type_tag(global.content_type()));
+                (ItemKind::Global, (vec![], type_tag(global.content_type())))
+            }
+        };
+
+        imports.push(Item {
+            kind,
+            module: entry.module().to_string(),
+            name: entry.field().to_string(),
+            params,
+            result,
+        });
+    }
+
+    function_types.extend(
+        module
+            .function_section()
+            .map(|s| s.entries())
+            .unwrap_or(&[])
+            .iter()
+            .map(|f| f.type_ref()),
+    );
+    global_types.extend(
+        module
+            .global_section()
+            .map(|s| s.entries())
+            .unwrap_or(&[])
+            .iter()
+            .map(|g| type_tag(g.global_type().content_type())),
+    );
+
+    let mut exports = Vec::new();
+    for entry in module.export_section().map(|s| s.entries()).unwrap_or(&[]) {
+        let (kind, (params, result)) = match entry.internal() {
+            Internal::Function(idx) => {
+                (ItemKind::Function, signature(function_types[*idx as usize]))
+            }
+            Internal::Table(_) => (ItemKind::Table, (vec![], TYPE_NONE)),
+            Internal::Memory(_) => (ItemKind::Memory, (vec![], TYPE_NONE)),
+            Internal::Global(idx) => (ItemKind::Global, (vec![], global_types[*idx as usize])),
+        };
+
+        exports.push(Item {
+            kind,
+            module: String::new(),
+            name: entry.field().to_string(),
+            params,
+            result,
+        });
+    }
+
+    (imports, exports)
+}
+
+fn status(result: Result<(), ErrorCode>) -> u32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => e as u32,
+    }
+}
+
+/// For when something goes wrong and there's no way to return an error code.
+fn trap(e: ErrorCode) -> wasmi::Trap {
+    wasmi::Trap::new(wasmi::TrapKind::Host(Box::new(SyscallError(e))))
+}
+
+/// Gets the error code back out of a trap made by `trap`.
+fn syscall_error(trap: &wasmi::Trap) -> Option<ErrorCode> {
+    match trap.kind() {
+        wasmi::TrapKind::Host(e) => e.downcast_ref::<SyscallError>().map(|e| e.0),
+        _ => None,
+    }
+}
+
+/// The error code a call that trapped is reported with.
+fn trap_code(trap: &wasmi::Trap) -> ErrorCode {
+    use wasmi::TrapKind;
+
+    match trap.kind() {
+        TrapKind::Unreachable => ErrorCode::Unreachable,
+        TrapKind::MemoryAccessOutOfBounds
+        | TrapKind::TableAccessOutOfBounds
+        | TrapKind::ElemUninitialized => ErrorCode::AccessOutOfBounds,
+        TrapKind::DivisionByZero => ErrorCode::DivisionByZero,
+        TrapKind::InvalidConversionToInt => ErrorCode::IntegerOverflow,
+        TrapKind::UnexpectedSignature => ErrorCode::IndirectCallMismatch,
+        TrapKind::StackOverflow => ErrorCode::StackOverflow,
+        TrapKind::Host(_) if panic_message(trap).is_some() => ErrorCode::Panicked,
+        TrapKind::Host(_) => syscall_error(trap).unwrap_or(ErrorCode::HostError),
+    }
+}
+
+/// What the process said, if the trap is from it calling `_panic`.
+fn panic_message(trap: &wasmi::Trap) -> Option<&str> {
+    match trap.kind() {
+        wasmi::TrapKind::Host(e) => e.downcast_ref::<Panicked>().map(|p| p.0.as_str()),
+        _ => None,
+    }
+}
+
+fn is_preempted(trap: &wasmi::Trap) -> bool {
+    match trap.kind() {
+        wasmi::TrapKind::Host(e) => e.downcast_ref::<Preempted>().is_some(),
+        _ => false,
+    }
+}
+
+/// Lets an `ErrorCode` travel through wasmi's error types (e.g. out of an import resolver) so we
+/// can get it back on the other side.
+#[derive(Debug)]
+struct SyscallError(ErrorCode);
+
+impl std::fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+impl wasmi::HostError for SyscallError {}
+
+/// What a task is stopped with when its slice is up. Never seen by anything but the scheduler.
+#[derive(Debug)]
+struct Preempted;
+
+impl std::fmt::Display for Preempted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "preempted")
+    }
+}
+
+impl wasmi::HostError for Preempted {}
+
+/// What a process that called `_panic` traps with. Carries its message up to whoever can report it.
+#[derive(Debug)]
+struct Panicked(String);
+
+impl std::fmt::Display for Panicked {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "panicked: {}", self.0)
+    }
+}
+
+impl wasmi::HostError for Panicked {}
+
+/// Where `_invoke`, `_poll` and `_wait` write what a call returned. Any of them can be null.
+#[derive(Clone, Copy)]
+struct ResultPtrs {
+    /// Gets the value it returned, or how long its panic message is if it panicked.
+    value: u32,
+    ty: u32,
+    /// Gets as much of its panic message as fits in `panic_message_length` bytes.
+    panic_message: u32,
+    panic_message_length: u32,
+}
+
+impl ResultPtrs {
+    /// Reads them from a syscall's arguments, starting at `first`.
+    fn from_args(args: &wasmi::RuntimeArgs, first: usize) -> Self {
+        ResultPtrs {
+            value: args.nth(first),
+            ty: args.nth(first + 1),
+            panic_message: args.nth(first + 2),
+            panic_message_length: args.nth(first + 3),
         }
     }
 }
 
+struct Task {
+    /// The spawned process it runs in.
+    process: u32,
+    /// The process that asked for it, and gets the result.
+    owner: u32,
+    /// Not there while it's running.
+    invocation: Option<wasmi::FuncInvocation<'static>>,
+    started: bool,
+    /// Set once it's done.
+    result: Option<Result<Option<wasmi::RuntimeValue>, wasmi::Trap>>,
+}
+
+/// The slice the running task is on.
+struct Slice {
+    /// How much more it can run before it's preempted.
+    fuel: i64,
+    /// How deep the call stack is when it's running its own code. It can only be preempted then.
+    depth: usize,
+}
+
+struct Channel {
+    /// Messages waiting to be received, never more than `capacity` of them.
+    queue: VecDeque<Message>,
+    capacity: usize,
+    sender_open: bool,
+    receiver_open: bool,
+}
+
+struct Message {
+    bytes: Vec<u8>,
+    /// Ends of other channels sent along with it.
+    handles: Vec<u32>,
+}
+
+struct SharedMemory {
+    mem: wasmi::MemoryRef,
+    /// The process that created it. Only it can bind it or get at it with syscalls.
+    owner: u32,
+}
+
+struct Endpoint {
+    channel: u32,
+    /// 0 while it's in a message, waiting to be received.
+    owner: u32,
+    /// Which end it is.
+    sender: bool,
+}
+
 struct Process {
-    module: wasmi::Module,
+    /// Not turned into a `wasmi::Module` until it's spawned, since that's when we know what its
+    /// limits are.
+    module: parity_wasm::elements::Module,
     bindings: BindingSet,
+    /// The process that created this one. Only it can bind or spawn it.
+    owner: u32,
+    imports: Vec<Item>,
+    exports: Vec<Item>,
+    limits: Limits,
+}
+
+/// What a process is allowed to use. It starts out with its creator's, which the creator can lower
+/// with `_set_limits` before spawning it.
+#[derive(Clone, Copy)]
+struct Limits {
+    /// How far any memory it has can grow, in 64KiB pages.
+    memory_pages: u32,
+    /// How far its table can grow.
+    table_elements: u32,
+    /// Largest module it can `_create`, in bytes.
+    module_size: u32,
+    /// How many calls into it can be in progress at once, nested inside each other.
+    call_depth: u32,
+    /// How many values its own code can have on its stack at once, counting locals.
+    value_stack: u32,
+}
+
+/// What the root process gets, and so the most anything can have.
+const HOST_LIMITS: Limits = Limits {
+    memory_pages: MAX_MEMORY_PAGES,
+    table_elements: MAX_TABLE_ELEMENTS,
+    module_size: MAX_MODULE_SIZE,
+    call_depth: MAX_CALL_DEPTH,
+    value_stack: MAX_VALUE_STACK,
+};
+
+impl Default for Limits {
+    fn default() -> Self {
+        HOST_LIMITS
+    }
+}
+
+/// An import or export of a module. The owned version of a `Descriptor`.
+struct Item {
+    kind: ItemKind,
+    module: String,
+    name: String,
+    params: Vec<u8>,
+    result: u8,
+}
+
+impl Item {
+    fn descriptor(&self) -> Descriptor<'_> {
+        Descriptor {
+            kind: self.kind,
+            module: &self.module,
+            name: &self.name,
+            params: &self.params,
+            result: self.result,
+        }
+    }
 }
 
 struct SpawnedProcess {
     module: wasmi::ModuleRef,
+    /// The process's own memory and function table, if it exports them. Syscalls it makes use
+    /// these.
+    mem: Option<wasmi::MemoryRef>,
+    table: Option<wasmi::TableRef>,
+    /// The process that spawned this one. Only it can invoke it.
+    owner: u32,
+    /// IDs of the bindings its imports go through, so they can be released along with it.
+    bindings: Vec<usize>,
+    imports: Vec<Item>,
+    exports: Vec<Item>,
+    /// How many more instructions it's allowed to run, if its owner set a limit.
+    fuel: Option<i64>,
+    limits: Limits,
+    /// The global its code counts how deep its stack is in. Not there for the root process.
+    stack_height: Option<wasmi::GlobalRef>,
+}
+
+impl SpawnedProcess {
+    fn new(module: wasmi::ModuleRef, owner: u32) -> Self {
+        let mem = module
+            .export_by_name("memory")
+            .and_then(|e| e.as_memory().cloned());
+        let table = module
+            .export_by_name("__indirect_function_table")
+            .and_then(|e| e.as_table().cloned());
+
+        SpawnedProcess {
+            module,
+            mem,
+            table,
+            owner,
+            bindings: Vec::new(),
+            imports: Vec::new(),
+            exports: Vec::new(),
+            fuel: None,
+            limits: HOST_LIMITS,
+            stack_height: None,
+        }
+    }
+}
+
+/// A function one process has bound into another's imports.
+struct Binding {
+    /// The process `func` runs as. The one that bound it, or the one it's an export of.
+    owner: u32,
+    func: wasmi::FuncRef,
+    /// Passed to `func` before the child's arguments, if it was bound with `_bind_env`.
+    env: Option<u32>,
 }
 
 #[derive(Default)]
 struct BindingSet {
     bindings: HashMap<String, wasmi::FuncRef>,
+    memories: HashMap<String, wasmi::MemoryRef>,
+    ids: Vec<usize>,
+    /// What it ended up importing, bound or not, since it might not export them.
+    memory: RefCell<Option<wasmi::MemoryRef>>,
+    table: RefCell<Option<wasmi::TableRef>>,
+    /// The process's, for anything the host has to make for it.
+    limits: Limits,
 }
 
 impl wasmi::ModuleImportResolver for BindingSet {
@@ -142,7 +2718,56 @@ impl wasmi::ModuleImportResolver for BindingSet {
         field_name: &str,
         _signature: &wasmi::Signature,
     ) -> Result<wasmi::FuncRef, wasmi::Error> {
-        Ok(self.bindings[field_name].clone())
+        // No binding over the top of this one, or you could get out of paying for things.
+        if field_name == GAS_IMPORT {
+            return Ok(resolve_syscall(field_name).unwrap());
+        }
+
+        self.bindings
+            .get(field_name)
+            .cloned()
+            .or_else(|| resolve_syscall(field_name))
+            .ok_or_else(|| wasmi::Error::Host(Box::new(SyscallError(ErrorCode::MissingImport))))
+    }
+
+    fn resolve_memory(
+        &self,
+        field_name: &str,
+        memory_type: &wasmi::MemoryDescriptor,
+    ) -> Result<wasmi::MemoryRef, wasmi::Error> {
+        let mem = match self.memories.get(field_name) {
+            // Whoever else it's shared with could grow it, so it has to be capped at our limit
+            // already.
+            Some(mem)
+                if mem
+                    .maximum()
+                    .is_some_and(|max| max.0 as u32 <= self.limits.memory_pages) =>
+            {
+                mem.clone()
+            }
+            Some(_) => {
+                return Err(wasmi::Error::Host(Box::new(SyscallError(
+                    ErrorCode::LimitExceeded,
+                ))))
+            }
+            None => alloc_memory(memory_type, &self.limits)
+                .map_err(|e| wasmi::Error::Host(Box::new(SyscallError(e))))?,
+        };
+        *self.memory.borrow_mut() = Some(mem.clone());
+
+        Ok(mem)
+    }
+
+    fn resolve_table(
+        &self,
+        _field_name: &str,
+        table_type: &wasmi::TableDescriptor,
+    ) -> Result<wasmi::TableRef, wasmi::Error> {
+        let table = alloc_table(table_type, &self.limits)
+            .map_err(|e| wasmi::Error::Host(Box::new(SyscallError(e))))?;
+        *self.table.borrow_mut() = Some(table.clone());
+
+        Ok(table)
     }
 }
 
@@ -152,181 +2777,386 @@ impl wasmi::Externals for HostExternals {
         index: usize,
         args: wasmi::RuntimeArgs,
     ) -> Result<Option<wasmi::RuntimeValue>, wasmi::Trap> {
-        dbg!(&index, &args);
+        let caller = self.caller();
+
+        // Gas gets paid all the time, that'd drown out everything else. It's checked first so it
+        // stays cheap, as does everything else when nothing's being traced.
+        if index == GAS_INDEX || !self.tracer.as_ref().is_some_and(|t| t.traces(caller)) {
+            return self.syscall(index, args);
+        }
 
+        // Bindings aren't syscalls, anything they do gets traced as whoever they run as.
+        let func = match SYSCALLS.iter().chain(SERVICES).find(|func| func.3 == index) {
+            Some(func) => func,
+            None => return self.syscall(index, args),
+        };
+
+        let values = args.as_ref().to_vec();
+        let start = self.started.elapsed();
+        let result = self.syscall(index, args);
+        let end = self.started.elapsed();
+
+        let status = match func.1.iter().position(|&(param, _)| param == "error") {
+            Some(i) => match values[i] {
+                RuntimeValue::I32(0) => None,
+                RuntimeValue::I32(ptr) => self
+                    .mem()
+                    .ok()
+                    .and_then(|mem| mem.get_value(ptr as u32).ok()),
+                _ => None,
+            },
+            None => match result {
+                Ok(Some(RuntimeValue::I32(code))) => Some(code as u32),
+                _ => None,
+            },
+        };
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record(caller, func, &values, &result, status, start..end);
+        }
+
+        result
+    }
+}
+
+impl HostExternals {
+    fn syscall(
+        &mut self,
+        index: usize,
+        args: wasmi::RuntimeArgs,
+    ) -> Result<Option<wasmi::RuntimeValue>, wasmi::Trap> {
         match index {
-            1 => Ok(None),
+            1 => {
+                self.pragma(args.nth(0), args.nth(1));
+                Ok(None)
+            }
             2 => {
-                self.new_idx += 16;
-                let idx = self.new_idx | 0b0001;
+                let bytecode_ptr: u32 = args.nth(0);
+                let bytecode_length: u32 = args.nth(1);
+                let result_ptr: u32 = args.nth(2);
 
-                let bytecode = self
-                    .mem
-                    .get(args.nth(0), args.nth::<u32>(1) as usize)
-                    .unwrap();
+                let result = self.create(bytecode_ptr, bytecode_length);
+                self.returning(result_ptr, result)
+            }
+            3 => Ok(Some(status(self.bind(&args, false)).into())),
+            4 => {
+                let handle: u32 = args.nth(0);
+                let result_ptr: u32 = args.nth(1);
+
+                let result = self.spawn(handle);
+                self.returning(result_ptr, result)
+            }
+            5 => Ok(Some(status(self.invoke(&args)).into())),
+            6 | 8 => {
+                let handle: u32 = args.nth(0);
+                let result_ptr: u32 = args.nth(1);
+
+                let result = self
+                    .items(handle, index == 8)
+                    .map(|items| items.len() as u32);
+                self.returning(result_ptr, result)
+            }
+            7 | 9 => {
+                let result_ptr: u32 = args.nth(4);
 
-                let module = match wasmi::Module::from_buffer(&bytecode) {
-                    Ok(m) => m,
-                    Err(_) => {
-                        return Ok(Some(0.into()));
-                    }
-                };
+                let result = self.describe(&args, index == 9);
+                self.returning(result_ptr, result)
+            }
+            10 => Ok(Some(status(self.kill(args.nth(0))).into())),
+            11 => Ok(Some(status(self.close(args.nth(0))).into())),
+            12 => Ok(Some(status(self.bind(&args, true)).into())),
+            13 => Ok(Some(status(self.bind_service(&args)).into())),
+            14 => Ok(Some(status(self.bind_export(&args)).into())),
+            15 => {
+                let msg = self.read_string(args.nth(0), args.nth(1)).map_err(trap)?;
 
-                let proc = Process {
-                    module,
-                    bindings: Default::default(),
-                };
+                println!("[{:#x}] {}", self.caller(), msg);
 
-                self.processes.insert(idx, proc);
+                Ok(None)
+            }
+            16 => Ok(Some((self.started.elapsed().as_nanos() as i64).into())),
+            17 => Ok(Some(status(self.read_memory(&args)).into())),
+            GAS_INDEX => self.gas(args.nth(0)).map(|_| None),
+            19 => Ok(Some(status(self.set_fuel(args.nth(0), args.nth(1))).into())),
+            20 => {
+                let result_ptr: u32 = args.nth(6);
 
-                Ok(Some(idx.into()))
+                let result = self.schedule_call(&args);
+                self.returning(result_ptr, result)
             }
-            3 => {
-                let handle: u32 = args.nth(0);
-                let fn_name_ptr: u32 = args.nth(1);
-                let fn_name_length: u32 = args.nth(2);
-                let fnptr: u32 = args.nth(3);
+            21 => {
+                let result = self.poll(args.nth(0), ResultPtrs::from_args(&args, 1));
+                Ok(Some(status(result).into()))
+            }
+            22 => {
+                let result = self.wait(args.nth(0), ResultPtrs::from_args(&args, 1));
+                Ok(Some(status(result).into()))
+            }
+            23 => Ok(Some(status(self.cancel(args.nth(0))).into())),
+            24 => {
+                let result = self.channel_create(args.nth(0), args.nth(1), args.nth(2));
+                Ok(Some(status(result).into()))
+            }
+            25 => Ok(Some(status(self.send(&args)).into())),
+            26 => Ok(Some(status(self.recv(&args)).into())),
+            27 => Ok(Some(status(self.give(args.nth(0), args.nth(1))).into())),
+            28 => {
+                let result_ptr: u32 = args.nth(2);
+
+                let result = self.memory_create(args.nth(0), args.nth(1));
+                self.returning(result_ptr, result)
+            }
+            29 => Ok(Some(status(self.bind_memory(&args)).into())),
+            30 | 31 => Ok(Some(status(self.copy_shared(&args, index == 31)).into())),
+            32 => Ok(Some(
+                status(self.write(args.nth(0), args.nth(1), args.nth(2))).into(),
+            )),
+            33 => Ok(Some(status(self.set_limits(&args)).into())),
+            34 => Err(self.panic(args.nth(0), args.nth(1))),
+            _ if index >= BINDING_BASE => self.call_binding(index - BINDING_BASE, args),
+            _ => panic!("Unimplemented function at {}", index),
+        }
+    }
+}
 
-                let proc = self.processes.get_mut(&handle).unwrap();
-                let fn_name = self.mem.get(fn_name_ptr, fn_name_length as usize).unwrap();
+/// Exit code for when the program couldn't be loaded, trapped, or leaked handles.
+const EXIT_FAILURE: i32 = 1;
+/// Exit code for when we weren't run properly.
+const EXIT_USAGE: i32 = 2;
 
-                let fn_name_str = String::from_utf8(fn_name).unwrap();
+// The test suite is `hello_world`'s `test`: build `wasm` with `cargo build --release`, then
+// `wasmos wasm/target/wasm32-unknown-unknown/release/hello_world.wasm test` should print 1337 and
+// exit 0.
+const USAGE: &str = "usage: wasmos <program.wasm|program.wat> [entry] [args...]";
 
-                let exp = self
-                    .module
-                    .export_by_name("__indirect_function_table")
-                    .unwrap();
+enum CliError {
+    Usage(String),
+    Failed(String),
+}
 
-                let table = exp.as_table().unwrap();
+impl CliError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Usage(_) => EXIT_USAGE,
+            CliError::Failed(_) => EXIT_FAILURE,
+        }
+    }
+}
 
-                assert!(
-                    fnptr < 1024,
-                    "you probably passed a *pointer* to memory not a table index. don't do that."
-                );
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CliError::Usage(msg) => write!(f, "{}\n{}", msg, USAGE),
+            CliError::Failed(msg) => write!(f, "{}", msg),
+        }
+    }
+}
 
-                let fnref = table.get(fnptr).unwrap().unwrap();
+/// Reads a program in, converting it from the text format if it's a `.wat`.
+fn read_program(path: &std::path::Path) -> Result<Vec<u8>, CliError> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| CliError::Failed(format!("couldn't read {}: {}", path.display(), e)))?;
 
-                proc.bindings.bindings.insert(fn_name_str, fnref);
+    if path.extension() == Some("wat".as_ref()) {
+        return wabt::wat2wasm(&bytes)
+            .map_err(|e| CliError::Failed(format!("couldn't parse {}: {}", path.display(), e)));
+    }
 
-                Ok(Some(0.into()))
-            }
-            4 => {
-                let handle: u32 = args.nth(0);
+    Ok(bytes)
+}
 
-                dbg!(handle);
+/// Parses command line arguments into what the entry point takes.
+fn parse_args(params: &[wasmi::ValueType], args: &[String]) -> Result<Vec<RuntimeValue>, CliError> {
+    use wasmi::ValueType::*;
 
-                self.new_idx += 16;
-                let idx = self.new_idx | 0b0010;
+    if params.len() != args.len() {
+        return Err(CliError::Usage(format!(
+            "the entry point takes {} arguments, got {}",
+            params.len(),
+            args.len()
+        )));
+    }
 
-                let proc = self.processes.remove(&handle).unwrap();
+    params
+        .iter()
+        .zip(args)
+        .map(|(ty, arg)| {
+            // Integers can be given signed or unsigned, they're the same bits either way.
+            let value = match ty {
+                I32 => arg
+                    .parse::<i32>()
+                    .ok()
+                    .or_else(|| arg.parse::<u32>().ok().map(|v| v as i32))
+                    .map(RuntimeValue::I32),
+                I64 => arg
+                    .parse::<i64>()
+                    .ok()
+                    .or_else(|| arg.parse::<u64>().ok().map(|v| v as i64))
+                    .map(RuntimeValue::I64),
+                F32 => arg.parse::<f32>().ok().map(|v| RuntimeValue::F32(v.into())),
+                F64 => arg.parse::<f64>().ok().map(|v| RuntimeValue::F64(v.into())),
+            };
 
-                let imports = wasmi::ImportsBuilder::default().with_resolver("env", &proc.bindings);
+            value.ok_or_else(|| CliError::Usage(format!("{:?} isn't a valid {:?}", arg, ty)))
+        })
+        .collect()
+}
 
-                let mi = ModuleInstance::new(&proc.module, &imports)
-                    .unwrap()
-                    .assert_no_start();
+fn format_value(value: RuntimeValue) -> String {
+    match value {
+        RuntimeValue::I32(v) => v.to_string(),
+        RuntimeValue::I64(v) => v.to_string(),
+        RuntimeValue::F32(v) => v.to_float().to_string(),
+        RuntimeValue::F64(v) => v.to_float().to_string(),
+    }
+}
 
-                let sp = SpawnedProcess { module: mi };
+/// How the root process's code failed, with what it said if it panicked.
+fn failure(e: wasmi::Error) -> String {
+    match e.as_host_error().and_then(|e| e.downcast_ref::<Panicked>()) {
+        Some(panicked) => panicked.to_string(),
+        None => format!("trapped: {}", e),
+    }
+}
 
-                self.spawned_processes.insert(idx, sp);
+/// Runs `entry` in the program at `path` as the root process, and returns what it returned. Fails
+/// if it left any handles open.
+fn run(path: &str, entry: &str, args: &[String]) -> Result<Option<RuntimeValue>, CliError> {
+    let wasm_binary = read_program(path.as_ref())?;
 
-                Ok(Some(idx.into()))
-            }
-            5 => {
-                let handle: u32 = args.nth(0);
-                let fn_name_ptr: u32 = args.nth(1);
-                let fn_name_length: u32 = args.nth(2);
-                let arg_ptr: u32 = args.nth(3);
-                let arg_ty_ptr: u32 = args.nth(4);
-                let arg_len: u32 = args.nth(5);
-                let result_ptr: u32 = args.nth(6);
+    let mut module: parity_wasm::elements::Module =
+        parity_wasm::deserialize_buffer(&wasm_binary)
+            .map_err(|e| CliError::Failed(format!("couldn't load {}: {}", path, e)))?;
+    // Its own memory and table can't get any bigger than anything else's. The ones it imports
+    // already get capped when they're made.
+    cap_module(&mut module, &HOST_LIMITS)
+        .map_err(|e| CliError::Failed(format!("couldn't load {}: {:?}", path, e)))?;
+    let module = wasmi::Module::from_parity_wasm_module(module)
+        .map_err(|e| CliError::Failed(format!("couldn't load {}: {}", path, e)))?;
 
-                let fn_name_bytes = self.mem.get(fn_name_ptr, fn_name_length as usize).unwrap();
-                let fn_name_str = String::from_utf8(fn_name_bytes).unwrap();
+    let imports = Imports::default();
+    let not_started = ModuleInstance::new(&module, &imports)
+        .map_err(|e| CliError::Failed(format!("couldn't instantiate {}: {}", path, e)))?;
 
-                let sp = self.spawned_processes.get_mut(&handle).unwrap();
+    let mut externals = HostExternals::new(not_started.not_started_instance().clone(), imports);
 
-                let exp = sp.module.export_by_name(&fn_name_str).unwrap();
-                let func = exp.as_func().unwrap();
+    let instance = not_started
+        .run_start(&mut externals)
+        .map_err(|e| CliError::Failed(format!("start function {}", failure(e.into()))))?;
 
-                let mut idx = arg_ptr;
+    let params = instance
+        .export_by_name(entry)
+        .and_then(|export| export.as_func().cloned())
+        .ok_or_else(|| CliError::Usage(format!("{} doesn't export a function {}", path, entry)))?
+        .signature()
+        .params()
+        .to_vec();
 
-                let arg_types = self.mem.get(arg_ty_ptr, arg_len as usize).unwrap();
+    let args = parse_args(&params, args)?;
 
-                let mut runtime_values = Vec::<wasmi::RuntimeValue>::new();
-                for (param, ty) in func.signature().params().iter().zip(arg_types) {
-                    use wasmi::nan_preserving_float::{F32, F64};
-                    use wasmi::ValueType;
+    let result = instance
+        .invoke_export(entry, &args, &mut externals)
+        .map_err(|e| CliError::Failed(format!("{} {}", entry, failure(e))))?;
 
-                    let rtv = match param {
-                        ValueType::I32 if ty == b'i' => {
-                            self.mem.get_value::<i32>(idx).unwrap().into()
-                        }
-                        ValueType::I64 if ty == b'I' => {
-                            self.mem.get_value::<i64>(idx).unwrap().into()
-                        }
-                        ValueType::F32 if ty == b'f' => {
-                            self.mem.get_value::<F32>(idx).unwrap().into()
-                        }
-                        ValueType::F64 if ty == b'F' => {
-                            self.mem.get_value::<F64>(idx).unwrap().into()
-                        }
-                        _ => panic!("type mismatch"),
-                    };
+    // Everything goes away when we exit anyway, but a program leaving things lying around is
+    // probably a bug. It's also how the test suite checks handles get reclaimed.
+    let leaks: Vec<String> = [
+        ("process handles", externals.live_handles()),
+        ("bindings", externals.bindings.len()),
+        ("jobs", externals.tasks.len()),
+        ("channels", externals.channels.len()),
+        ("shared memories", externals.shared_memories.len()),
+    ]
+    .iter()
+    .filter(|(_, count)| *count != 0)
+    .map(|(what, count)| format!("{} {}", count, what))
+    .collect();
 
-                    // yes this means we have padding bytes for 32 bit types
-                    // i do not care.
-                    idx += 8;
+    if !leaks.is_empty() {
+        return Err(CliError::Failed(format!(
+            "{} leaked {}",
+            entry,
+            leaks.join(", ")
+        )));
+    }
 
-                    runtime_values.push(rtv);
-                }
+    Ok(result)
+}
 
-                dbg!(&runtime_values);
-
-                let result = sp
-                    .module
-                    .invoke_export(&fn_name_str, &runtime_values, &mut NopExternals)
-                    .unwrap();
-
-                match result {
-                    Some(r) => {
-                        use wasmi::RuntimeValue::*;
-                        match r {
-                            I32(v) => self.mem.set_value(result_ptr, v),
-                            I64(v) => self.mem.set_value(result_ptr, v),
-                            F32(v) => self.mem.set_value(result_ptr, v),
-                            F64(v) => self.mem.set_value(result_ptr, v),
-                        }
-                        .unwrap();
-                    }
-                    None => {}
-                };
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
 
-                Ok(Some(0.into()))
-            }
-            _ => panic!("Unimplemented function at {}", index),
+    let result = match args.as_slice() {
+        [] => Err(CliError::Usage("no program given".to_string())),
+        [path] => run(path, "main", &[]),
+        [path, entry, args @ ..] => run(path, entry, args),
+    };
+
+    match result {
+        Ok(Some(value)) => println!("{}", format_value(value)),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("wasmos: {}", e);
+            std::process::exit(e.exit_code());
         }
     }
 }
 
-fn main() {
-    // if you're getting a build error here, go to 'wasm' and do `cargo build --release`
-    let wasm_binary =
-        include_bytes!("../wasm/target/wasm32-unknown-unknown/release/hello_world.wasm");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Somewhere a `Tracer` can write to that can be read back afterwards.
+    #[derive(Clone, Default)]
+    struct Captured(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
 
-    // Load wasm binary and prepare it for instantiation.
-    let module = wasmi::Module::from_buffer(wasm_binary.as_ref()).expect("failed to load wasm");
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
 
-    let instance = ModuleInstance::new(&module, &Imports {})
-        .expect("failed to instantiate wasm module")
-        .assert_no_start();
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
 
-    let mut externals = HostExternals::new(instance.clone());
+    #[test]
+    fn trace_non_finite_floats() {
+        use wasmi::ValueType::{F32, F64};
 
-    assert_eq!(
-        instance
-            .invoke_export("test", &[], &mut externals,)
-            .expect("failed to execute export"),
-        Some(RuntimeValue::I32(1337)),
-    );
+        const FUNC: HostFunc = (
+            "_floats",
+            &[("nan", F32), ("inf", F64), ("neg_inf", F64), ("half", F64)],
+            Some(F64),
+            0,
+        );
+
+        let out = Captured::default();
+        let mut tracer = Tracer {
+            pids: None,
+            out: Box::new(out.clone()),
+        };
+
+        let args = [
+            RuntimeValue::F32(f32::NAN.into()),
+            RuntimeValue::F64(f64::INFINITY.into()),
+            RuntimeValue::F64(f64::NEG_INFINITY.into()),
+            RuntimeValue::F64(0.5.into()),
+        ];
+        let result = Ok(Some(RuntimeValue::F64(f64::NAN.into())));
+        let time = std::time::Duration::ZERO..std::time::Duration::ZERO;
+        tracer.record(ROOT_PROCESS, &FUNC, &args, &result, None, time);
+
+        let line = String::from_utf8(out.0.borrow().clone()).unwrap();
+        assert_eq!(
+            line,
+            concat!(
+                r#"{"time":0.000000,"pid":"0x2","syscall":"_floats","#,
+                r#""args":{"nan":"NaN","inf":"inf","neg_inf":"-inf","half":0.5},"#,
+                r#""result":"NaN","duration":0.000000}"#,
+                "\n"
+            )
+        );
+    }
 }
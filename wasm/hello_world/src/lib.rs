@@ -5,16 +5,20 @@ extern crate wee_alloc;
 
 use core::mem::MaybeUninit;
 
-use wasmcorelib::{_bind, _create, _invoke, _spawn, params};
+use wasmcorelib::{_bind, _create, _invoke, _kill, _spawn, _write, eprintln, params, println};
 
 // Use `wee_alloc` as the global allocator.
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
+/// The test suite. Returns 1337 if everything passed, and traps if anything didn't. Run it from
+/// `wasm` with `wasmos target/wasm32-unknown-unknown/release/hello_world.wasm test`, which also
+/// fails if it leaks any handles.
 #[no_mangle]
 pub extern "C" fn test() -> i32 {
     // exports 1 function test() -> i32 { 1337 }
     // no dependencies
+    // the rest are in the text format, but this one's binary so that keeps working too
     let bytecode = alloc::vec![
         0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f,
         0x03, 0x02, 0x01, 0x00, 0x07, 0x08, 0x01, 0x04, 0x74, 0x65, 0x73, 0x74, 0x00, 0x00, 0x0a,
@@ -25,13 +29,249 @@ pub extern "C" fn test() -> i32 {
     // has one export, add(i32) -> i32
     // returns lhs + frob(rhs)
     //
-    let more_advanced_bytecode = alloc::vec![
-        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0c, 0x02, 0x60, 0x01, 0x7f, 0x01,
-        0x7f, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f, 0x02, 0x0c, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x04,
-        0x66, 0x72, 0x6f, 0x62, 0x00, 0x00, 0x03, 0x02, 0x01, 0x01, 0x07, 0x07, 0x01, 0x03, 0x61,
-        0x64, 0x64, 0x00, 0x01, 0x0a, 0x0b, 0x01, 0x09, 0x00, 0x20, 0x00, 0x20, 0x01, 0x10, 0x00,
-        0x6a, 0x0b,
-    ];
+    let more_advanced_bytecode = br#"
+        (module
+          (import "env" "frob" (func $frob (param i32) (result i32)))
+          (func (export "add") (param i32 i32) (result i32)
+            (i32.add (local.get 0) (call $frob (local.get 1)))))
+    "#;
+
+    // imports env._create, env._spawn and env._invoke, and exports its memory
+    // has one export, grandchild() -> i32
+    // creates and spawns `bytecode` (from its own memory) and returns what its test() returns
+    let nested_bytecode = br#"
+        (module
+          (import "env" "_create" (func $create (param i32 i32 i32) (result i32)))
+          (import "env" "_spawn" (func $spawn (param i32 i32) (result i32)))
+          (import "env" "_invoke"
+            (func $invoke (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0)
+            "\00\61\73\6d\01\00\00\00\01\05\01\60\00\01\7f\03\02\01\00\07\08\01"
+            "\04\74\65\73\74\00\00\0a\07\01\05\00\41\b9\0a\0b")
+          (data (i32.const 64) "test")
+          (func (export "grandchild") (result i32)
+            (drop (call $invoke
+              (call $spawn (call $create (i32.const 0) (i32.const 38) (i32.const 0)) (i32.const 0))
+              (i32.const 64) (i32.const 4)
+              (i32.const 0) (i32.const 0) (i32.const 0)
+              (i32.const 128) (i32.const 0) (i32.const 0) (i32.const 0)))
+            (i32.load (i32.const 128))))
+    "#;
+
+    // imports env.log(i32, i32), env.clock() -> i64 and env.add(i32, i32) -> i32, and exports its
+    // memory
+    // has one export, run() -> i32
+    // logs "hello from a child", checks the time, and returns add(132, 205)
+    let wired_bytecode = br#"
+        (module
+          (import "env" "log" (func $log (param i32 i32)))
+          (import "env" "clock" (func $clock (result i64)))
+          (import "env" "add" (func $add (param i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "hello from a child")
+          (func (export "run") (result i32)
+            (call $log (i32.const 0) (i32.const 18))
+            (drop (call $clock))
+            (call $add (i32.const 132) (i32.const 205))))
+    "#;
+
+    // exports its memory and a bump allocator as __wasmos_alloc
+    // has three more exports, sum(ptr: i32, len: i32) -> i32, echo(ptr: i32, len: i32) -> i64 and
+    // mix(i32, i64, f32, f64) -> f64
+    // sum adds up the bytes of the buffer, echo returns it as is, mix adds up its arguments
+    let buffers_bytecode = br#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (func (export "__wasmos_alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (func (export "sum") (param $ptr i32) (param $len i32) (result i32)
+            (local $acc i32)
+            (block $done
+              (loop $top
+                (br_if $done (i32.eqz (local.get $len)))
+                (local.set $acc (i32.add (local.get $acc) (i32.load8_u (local.get $ptr))))
+                (local.set $ptr (i32.add (local.get $ptr) (i32.const 1)))
+                (local.set $len (i32.sub (local.get $len) (i32.const 1)))
+                (br $top)))
+            (local.get $acc))
+          (func (export "echo") (param $ptr i32) (param $len i32) (result i64)
+            (i64.or
+              (i64.extend_i32_u (local.get $ptr))
+              (i64.shl (i64.extend_i32_u (local.get $len)) (i64.const 32))))
+          (func (export "mix") (param i32 i64 f32 f64) (result f64)
+            (f64.add
+              (f64.add (f64.convert_i32_s (local.get 0)) (f64.convert_i64_s (local.get 1)))
+              (f64.add (f64.promote_f32 (local.get 2)) (local.get 3)))))
+    "#;
+
+    // has two exports, count(n: i32) -> i32 and spin()
+    // count loops n times and returns n, spin loops forever
+    let spin_bytecode = br#"
+        (module
+          (func (export "count") (param $n i32) (result i32)
+            (local $i i32)
+            (block $done
+              (loop $top
+                (br_if $done (i32.ge_u (local.get $i) (local.get $n)))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $top)))
+            (local.get $i))
+          (func (export "spin")
+            (loop $top (br $top))))
+    "#;
+
+    // has one export, stash(n: i32, spins: i32) -> i32
+    // stores n at address 0, loops spins times, then returns what's at address 0
+    let stash_bytecode = br#"
+        (module
+          (memory 1)
+          (func (export "stash") (param $n i32) (param $spins i32) (result i32)
+            (i32.store (i32.const 0) (local.get $n))
+            (block $done
+              (loop $top
+                (br_if $done (i32.eqz (local.get $spins)))
+                (local.set $spins (i32.sub (local.get $spins) (i32.const 1)))
+                (br $top)))
+            (i32.load (i32.const 0))))
+    "#;
+
+    // echo(rx: i32, tx: i32) -> i32 receives a message of up to 64 bytes on rx and sends it back
+    // out on tx, returning the error code of whichever failed
+    let echo_bytecode = br#"
+        (module
+          (import "env" "_recv" (func $recv (param i32 i32 i32 i32 i32 i32 i32) (result i32)))
+          (import "env" "_send" (func $send (param i32 i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (func (export "echo") (param $rx i32) (param $tx i32) (result i32)
+            (local $status i32)
+            (local.set $status (call $recv
+              (local.get $rx) (i32.const 0) (i32.const 64) (i32.const 64)
+              (i32.const 0) (i32.const 0) (i32.const 0)))
+            (if (local.get $status) (then (return (local.get $status))))
+            (call $send
+              (local.get $tx) (i32.const 0) (i32.load (i32.const 64))
+              (i32.const 0) (i32.const 0))))
+    "#;
+
+    // imports env.log(i32, i32) and its memory as env.shared, which has to be at least a page
+    // has three exports, fill(ptr: i32, len: i32, byte: i32), sum(ptr: i32, len: i32) -> i32 and
+    // shout(ptr: i32, len: i32)
+    // fill sets len bytes to byte, sum adds them up, shout logs them
+    let shared_bytecode = br#"
+        (module
+          (import "env" "log" (func $log (param i32 i32)))
+          (import "env" "shared" (memory 1))
+          (func (export "fill") (param $ptr i32) (param $len i32) (param $byte i32)
+            (block $done
+              (loop $next
+                (br_if $done (i32.eqz (local.get $len)))
+                (i32.store8 (local.get $ptr) (local.get $byte))
+                (local.set $ptr (i32.add (local.get $ptr) (i32.const 1)))
+                (local.set $len (i32.sub (local.get $len) (i32.const 1)))
+                (br $next))))
+          (func (export "sum") (param $ptr i32) (param $len i32) (result i32)
+            (local $acc i32)
+            (block $done
+              (loop $next
+                (br_if $done (i32.eqz (local.get $len)))
+                (local.set $acc (i32.add (local.get $acc) (i32.load8_u (local.get $ptr))))
+                (local.set $ptr (i32.add (local.get $ptr) (i32.const 1)))
+                (local.set $len (i32.sub (local.get $len) (i32.const 1)))
+                (br $next)))
+            (local.get $acc))
+          (func (export "shout") (param $ptr i32) (param $len i32)
+            (call $log (local.get $ptr) (local.get $len))))
+    "#;
+
+    // imports env.__pid and env.__abi_version, and a memory and table nobody binds
+    // has three exports, pid() -> i32, abi() -> i32 and pages() -> i32
+    // which return the globals and how big its memory is
+    let globals_bytecode = br#"
+        (module
+          (import "env" "__pid" (global $pid i32))
+          (import "env" "__abi_version" (global $abi i32))
+          (import "env" "memory" (memory 2))
+          (import "env" "table" (table 4 funcref))
+          (func (export "pid") (result i32) (global.get $pid))
+          (func (export "abi") (result i32) (global.get $abi))
+          (func (export "pages") (result i32) (memory.size)))
+    "#;
+
+    // imports a memory bigger than the host will give it
+    let greedy_bytecode = br#"
+        (module (import "env" "memory" (memory 1000)))
+    "#;
+
+    // recurse(n) goes n calls deeper into itself by way of again(pid, n - 1), which gets bound to
+    // something that invokes recurse(n - 1) on the process with that pid. deep(n) goes n calls
+    // deeper on its own, and returns n
+    let recursive_bytecode = br#"
+        (module
+            (import "env" "__pid" (global $pid i32))
+            (import "env" "again" (func $again (param i32 i32) (result i32)))
+            (func (export "recurse") (param $n i32) (result i32)
+                (if (result i32) (i32.eqz (local.get $n))
+                    (then (i32.const 0))
+                    (else (call $again (global.get $pid) (i32.sub (local.get $n) (i32.const 1))))))
+            (func $deep (export "deep") (param $n i32) (result i32)
+                (if (result i32) (i32.eqz (local.get $n))
+                    (then (i32.const 0))
+                    (else (i32.add
+                        (i32.const 1)
+                        (call $deep (i32.sub (local.get $n) (i32.const 1))))))))
+    "#;
+
+    // each export traps in its own way (or several, depending on what it's given), except ok(),
+    // which doesn't. ok() is also in the table, at 1, with the wrong type for indirect()
+    let trapping_bytecode = br#"
+        (module
+            (memory 1)
+            (table 2 funcref)
+            (elem (i32.const 1) $ok)
+            (type $nullary (func))
+            (func (export "panic") unreachable)
+            (func (export "load") (result i32) (i32.load (i32.const 0x10000)))
+            (func (export "divide") (param $a i32) (param $b i32) (result i32)
+                (i32.div_s (local.get $a) (local.get $b)))
+            (func (export "truncate") (param $f f32) (result i32)
+                (i32.trunc_f32_s (local.get $f)))
+            (func (export "indirect") (param $i i32)
+                (call_indirect (type $nullary) (local.get $i)))
+            (func $ok (export "ok") (result i32) (i32.const 1337)))
+    "#;
+
+    // panics with a message the way wasmcorelib's panic handler does, or with one that's out of
+    // bounds
+    let panicking_bytecode = br#"
+        (module
+            (import "env" "_panic" (func $panic (param i32 i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 16) "oh no at src/lib.rs:1:1")
+            (func (export "panic") (call $panic (i32.const 16) (i32.const 23)))
+            (func (export "garbled") (call $panic (i32.const 0x10000) (i32.const 4))))
+    "#;
+
+    // grows its memory, and creates an empty module, as far as its limits let it
+    let limited_bytecode = br#"
+        (module
+            (import "env" "_create" (func $create (param i32 i32 i32) (result i32)))
+            (import "env" "_close" (func $close (param i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 16) "\00asm\01\00\00\00")
+            (func (export "grow") (param i32) (result i32)
+                (memory.grow (local.get 0)))
+            (func (export "create") (result i32)
+                (local $handle i32)
+                (local.set $handle (call $create (i32.const 16) (i32.const 8) (i32.const 4)))
+                (if (i32.load (i32.const 4))
+                    (then (return (i32.load (i32.const 4)))))
+                (call $close (local.get $handle))))
+    "#;
 
     unsafe {
         let handle = _create(
@@ -41,9 +281,9 @@ pub extern "C" fn test() -> i32 {
         );
 
         let fn_name = "test";
-        let mut output: MaybeUninit<i32> = MaybeUninit::uninit();
+        let mut output: MaybeUninit<u64> = MaybeUninit::uninit();
 
-        let spawned_handle = _spawn(handle);
+        let spawned_handle = _spawn(handle, core::ptr::null_mut());
 
         _invoke(
             spawned_handle,
@@ -52,10 +292,15 @@ pub extern "C" fn test() -> i32 {
             core::ptr::null(),
             core::ptr::null(),
             0,
-            output.as_mut_ptr().cast::<u64>(),
+            output.as_mut_ptr(),
+            core::ptr::null_mut(),
+            core::ptr::null_mut(),
+            0,
         );
 
         assert!(output.assume_init() == 1337);
+
+        assert!(_kill(spawned_handle) == 0);
     }
 
     unsafe {
@@ -69,7 +314,7 @@ pub extern "C" fn test() -> i32 {
         let binding_name_ptr = binding_name.as_ptr();
         let binding_name_len = binding_name.len();
 
-        // We don't have the support for the host to call using a captured environment
+        // Plain _bind doesn't pass along a captured environment (that's _bind_env)
         // So we have to use a function pointer here (Which directly corresponds to a index)
         let func: fn(i32) -> i32 = |x| x * 10 + 5;
 
@@ -80,7 +325,7 @@ pub extern "C" fn test() -> i32 {
             func as *const u8,
         );
 
-        let new_handle = _spawn(handle);
+        let new_handle = _spawn(handle, core::ptr::null_mut());
 
         let invoking_name = "add";
         let invoking_name_ptr = invoking_name.as_ptr();
@@ -95,7 +340,7 @@ pub extern "C" fn test() -> i32 {
         let args: [u64; 2] = [132 as u64, 120 as u64];
         let argtypes = b"ii";
 
-        let mut output: MaybeUninit<i32> = MaybeUninit::uninit();
+        let mut output: MaybeUninit<u64> = MaybeUninit::uninit();
         _invoke(
             new_handle,
             invoking_name_ptr,
@@ -103,20 +348,761 @@ pub extern "C" fn test() -> i32 {
             args.as_ptr(),
             argtypes.as_ptr(),
             args.len() as u32,
-            output.as_mut_ptr().cast::<u64>(),
+            output.as_mut_ptr(),
+            core::ptr::null_mut(),
+            core::ptr::null_mut(),
+            0,
         );
 
         assert!(output.assume_init() == 1337);
+
+        assert!(_kill(new_handle) == 0);
+        assert!(_kill(new_handle) != 0);
     }
 
-    let mut handle = wasmcorelib::create(&more_advanced_bytecode).unwrap();
+    let mut handle = wasmcorelib::create(more_advanced_bytecode).unwrap();
+
+    {
+        use wasmcorelib::{ItemType, ValueType};
+
+        let imports: alloc::vec::Vec<_> = handle.imports().unwrap().collect();
+        assert!(imports.len() == 1);
+        assert!(imports[0].module == "env" && imports[0].field == "frob");
+        assert!(
+            imports[0].ty
+                == ItemType::Function {
+                    params: alloc::vec![ValueType::I32],
+                    result: Some(ValueType::I32),
+                }
+        );
+
+        let exports: alloc::vec::Vec<_> = handle.exports().unwrap().collect();
+        assert!(exports.len() == 1);
+        assert!(exports[0].name == "add");
+        assert!(
+            exports[0].ty
+                == ItemType::Function {
+                    params: alloc::vec![ValueType::I32, ValueType::I32],
+                    result: Some(ValueType::I32),
+                }
+        );
+    }
 
     handle
         .bind("frob", (|x| x * 10 + 5) as fn(i32) -> i32)
         .unwrap();
     let mut proc = handle.spawn().unwrap();
 
-    assert!(proc.invoke("add", params!(132_u32, 120_u32)).unwrap() as i32 == 1337);
+    assert!(
+        proc.invoke::<i32>("add", params!(132_u32, 120_u32))
+            .unwrap()
+            == 1337
+    );
+
+    let offset = 1000;
+    let mut handle = wasmcorelib::create(more_advanced_bytecode).unwrap();
+    handle.bind("frob", move |x: i32| x + offset).unwrap();
+    let mut proc = handle.spawn().unwrap();
+
+    assert!(
+        proc.invoke::<i32>("add", params!(132_u32, 205_u32))
+            .unwrap()
+            == 1337
+    );
+
+    // Goes straight to the host, and straight to proc, without coming back through us
+    let mut handle = wasmcorelib::create(wired_bytecode).unwrap();
+    assert!(matches!(
+        handle.bind_service("log", "not a service"),
+        Err(wasmcorelib::BindProcessError::NoSuchService)
+    ));
+    handle.bind_service("log", "log").unwrap();
+    handle.bind_service("clock", "clock").unwrap();
+    handle.bind_export("add", &proc, "add").unwrap();
+    let mut wired = handle.spawn().unwrap();
+
+    assert!(wired.invoke::<i32>("run", params!()).unwrap() == 1337);
+    wired.kill().unwrap();
+
+    let mut buffers = wasmcorelib::create(buffers_bytecode)
+        .unwrap()
+        .spawn()
+        .unwrap();
+
+    assert!(buffers.invoke::<i32>("sum", params!("hello")).unwrap() == 532);
+    assert!(
+        buffers
+            .invoke_buffer("echo", params!(&b"wasmos"[..]))
+            .unwrap()
+            == b"wasmos"
+    );
+    #[derive(wasmcorelib::IntoParams, Clone, Copy)]
+    struct Mix {
+        a: i32,
+        b: i64,
+        c: f32,
+        d: f64,
+    }
+
+    let mix = Mix {
+        a: -1,
+        b: 1000,
+        c: 0.5,
+        d: 337.5,
+    };
+
+    use wasmcorelib::IntoParams;
+    assert!(buffers.invoke::<f64>("mix", mix.into_params()).unwrap() == 1337.0);
+    assert!(
+        buffers
+            .invoke::<f64>("mix", params!(mix.a, mix.b, mix.c, mix.d))
+            .unwrap()
+            == 1337.0
+    );
+    assert!(proc.invoke::<i32>("add", params!('A', true)).unwrap() == 1066);
+
+    // add takes two i32s, which a buffer can be, but proc has nowhere to put it
+    assert!(matches!(
+        proc.invoke::<i32>("add", params!("hello")),
+        Err(wasmcorelib::InvokeError::AllocationFailed)
+    ));
+
+    // These have memory to put a buffer in, but no allocator to make room in it
+    let no_allocator: [&[u8]; 2] = [
+        br#"(module
+            (memory (export "memory") 1)
+            (func (export "len") (param i32 i32) (result i32) (local.get 1)))"#,
+        br#"(module
+            (memory (export "memory") 1)
+            (global (export "__wasmos_alloc") i32 (i32.const 0))
+            (func (export "len") (param i32 i32) (result i32) (local.get 1)))"#,
+    ];
+    for bytecode in no_allocator.iter() {
+        let mut unallocating = wasmcorelib::create(bytecode).unwrap().spawn().unwrap();
+        assert!(matches!(
+            unallocating.invoke::<i32>("len", params!("hello")),
+            Err(wasmcorelib::InvokeError::AllocationFailed)
+        ));
+        assert!(
+            unallocating
+                .invoke::<i32>("len", params!(0_i32, 5_i32))
+                .unwrap()
+                == 5
+        );
+    }
+
+    // Its allocator hands out anything over 16 bytes at the very end of its memory, and fails
+    // anything over 64. It counts how many bytes it's been given back.
+    let mut freeing = wasmcorelib::create(
+        br#"(module
+            (memory (export "memory") 1)
+            (global $next (mut i32) (i32.const 1024))
+            (global $freed (mut i32) (i32.const 0))
+            (func (export "__wasmos_alloc") (param $len i32) (result i32)
+              (if (i32.gt_u (local.get $len) (i32.const 64)) (then (return (i32.const 0))))
+              (if (i32.gt_u (local.get $len) (i32.const 16)) (then (return (i32.const 0xfffc))))
+              (global.set $next (i32.add (global.get $next) (local.get $len)))
+              (i32.sub (global.get $next) (local.get $len)))
+            (func (export "__wasmos_free") (param i32 i32)
+              (global.set $freed (i32.add (global.get $freed) (local.get 1))))
+            (func (export "freed") (result i32) (global.get $freed))
+            (func (export "pair") (param i32 i32 i32 i32)))"#,
+    )
+    .unwrap()
+    .spawn()
+    .unwrap();
+    // The first buffer gets given back when the second doesn't fit, and so does the second
+    assert!(matches!(
+        freeing.invoke::<()>("pair", params!("ab", &[0u8; 32][..])),
+        Err(wasmcorelib::InvokeError::OutOfBounds)
+    ));
+    assert!(freeing.invoke::<i32>("freed", params!()).unwrap() == 34);
+    // The second never got allocated at all
+    assert!(matches!(
+        freeing.invoke::<()>("pair", params!("ab", &[0u8; 100][..])),
+        Err(wasmcorelib::InvokeError::AllocationFailed)
+    ));
+    assert!(freeing.invoke::<i32>("freed", params!()).unwrap() == 36);
+    assert!(freeing.invoke::<()>("pair", params!("ab", "cd")).is_ok());
+    assert!(freeing.invoke::<i32>("freed", params!()).unwrap() == 36);
+    drop(freeing);
+
+    {
+        use wasmcorelib::{InvokeError, ValueType};
+
+        match proc.invoke::<i32>("add", params!(132_u32)) {
+            Err(InvokeError::ArityMismatch { expected, provided }) => {
+                assert!(expected == [ValueType::I32, ValueType::I32]);
+                assert!(provided == [ValueType::I32]);
+            }
+            _ => panic!("add accepted too few arguments"),
+        }
+
+        match buffers.invoke::<i32>("sum", params!(132_u32, "hello")) {
+            Err(InvokeError::ArityMismatch { expected, provided }) => {
+                assert!(expected == [ValueType::I32, ValueType::I32]);
+                assert!(provided == [ValueType::I32; 3]);
+            }
+            _ => panic!("sum accepted too many arguments"),
+        }
+
+        let wrong_types = unsafe {
+            wasmcorelib::Params::new(
+                alloc::vec![132, 205],
+                alloc::vec![b'I', b'i'], // i64, i32
+            )
+        };
+        match proc.invoke::<i32>("add", wrong_types) {
+            Err(InvokeError::TypeMismatch { expected, provided }) => {
+                assert!(expected == [ValueType::I32, ValueType::I32]);
+                assert!(provided == [ValueType::I64, ValueType::I32]);
+            }
+            _ => panic!("add accepted an i64"),
+        }
+    }
+
+    let mut nested = wasmcorelib::create(nested_bytecode)
+        .unwrap()
+        .spawn()
+        .unwrap();
+
+    assert!(nested.invoke::<i32>("grandchild", params!()).unwrap() == 1337);
+    assert!(matches!(
+        nested.invoke::<i64>("grandchild", params!()),
+        Err(wasmcorelib::InvokeError::ResultMismatch {
+            returned: Some(wasmcorelib::ValueType::I32)
+        })
+    ));
+    // takes the grandchild with it
+    nested.kill().unwrap();
+
+    let mut spin = wasmcorelib::create(spin_bytecode).unwrap().spawn().unwrap();
+
+    // Takes a good few slices, and gets resumed after each one
+    assert!(spin.invoke::<i32>("count", params!(100_000_u32)).unwrap() == 100_000);
+
+    spin.set_fuel(Some(50_000)).unwrap();
+    assert!(matches!(
+        spin.invoke::<i32>("count", params!(100_000_u32)),
+        Err(wasmcorelib::InvokeError::OutOfFuel)
+    ));
+    assert!(matches!(
+        spin.invoke::<()>("spin", params!()),
+        Err(wasmcorelib::InvokeError::OutOfFuel)
+    ));
+
+    spin.set_fuel(None).unwrap();
+    assert!(spin.invoke::<i32>("count", params!(5_u32)).unwrap() == 5);
+
+    let mut spinners: alloc::vec::Vec<_> = (0..3)
+        .map(|_| wasmcorelib::create(spin_bytecode).unwrap().spawn().unwrap())
+        .collect();
+
+    // One of them never finishes, the others still get their turns
+    let forever = spinners[0].invoke_async::<()>("spin", params!()).unwrap();
+    let mut big = spinners[1]
+        .invoke_async::<i32>("count", params!(50_000_u32))
+        .unwrap();
+    let small = spinners[2]
+        .invoke_async::<i32>("count", params!(10_u32))
+        .unwrap();
+
+    assert!(small.wait().unwrap() == 10);
+    // It got its turns in between the big one's, rather than after it had finished
+    assert!(big.try_wait().is_none());
+
+    let big = loop {
+        if let Some(result) = big.try_wait() {
+            break result;
+        }
+    };
+    assert!(big.unwrap() == 50_000);
+
+    // Can't get anything out of it until it's done, and it's not going to be
+    let mut wrong = spinners[1]
+        .invoke_async::<i64>("count", params!(1_u32))
+        .unwrap();
+    assert!(wrong.try_wait().is_none());
+    drop(forever);
+    assert!(matches!(
+        wrong.try_wait(),
+        Some(Err(wasmcorelib::InvokeError::ResultMismatch {
+            returned: Some(wasmcorelib::ValueType::I32)
+        }))
+    ));
+
+    // Killing the process cancels what's queued up in it
+    let orphan = spinners[2].invoke_async::<()>("spin", params!()).unwrap();
+    spinners.pop().unwrap().kill().unwrap();
+    assert!(matches!(
+        orphan.wait(),
+        Err(wasmcorelib::InvokeError::InvalidHandle)
+    ));
+    drop(spinners);
+
+    // A call into a process doesn't start while another one is partway through in it
+    let mut stash = wasmcorelib::create(stash_bytecode)
+        .unwrap()
+        .spawn()
+        .unwrap();
+    let mut first = stash
+        .invoke_async::<i32>("stash", params!(1_i32, 100_000_i32))
+        .unwrap();
+    assert!(first.try_wait().is_none());
+    let second = stash
+        .invoke_async::<i32>("stash", params!(2_i32, 0_i32))
+        .unwrap();
+    assert!(second.wait().unwrap() == 2);
+    // It had to finish first
+    assert!(matches!(first.try_wait(), Some(Ok(1))));
+
+    let mut first = stash
+        .invoke_async::<i32>("stash", params!(3_i32, 100_000_i32))
+        .unwrap();
+    assert!(first.try_wait().is_none());
+    assert!(stash.invoke::<i32>("stash", params!(4_i32, 0_i32)).unwrap() == 4);
+    assert!(matches!(first.try_wait(), Some(Ok(3))));
+
+    // Fanning calls out to one process and awaiting them in any order gets each one's result
+    let (second, first) = {
+        let mut both = core::pin::pin!(async {
+            let first = stash
+                .invoke_async::<i32>("stash", params!(5_i32, 50_000_i32))
+                .unwrap();
+            let second = stash
+                .invoke_async::<i32>("stash", params!(6_i32, 50_000_i32))
+                .unwrap();
+
+            (second.await.unwrap(), first.await.unwrap())
+        });
+        let mut cx = core::task::Context::from_waker(core::task::Waker::noop());
+        loop {
+            use core::future::Future;
+
+            if let core::task::Poll::Ready(results) = both.as_mut().poll(&mut cx) {
+                break results;
+            }
+        }
+    };
+    assert!(first == 5);
+    assert!(second == 6);
+    drop(stash);
+
+    let (mut tx, mut rx) = wasmcorelib::channel(2);
+    assert!(matches!(rx.try_recv(), Err(wasmcorelib::RecvError::Empty)));
+    tx.try_send(b"hello").unwrap();
+    tx.try_send(b"world").unwrap();
+    assert!(matches!(
+        tx.try_send(b"!"),
+        Err(wasmcorelib::SendError::Full)
+    ));
+    assert!(rx.try_recv().unwrap().bytes == b"hello");
+    tx.try_send(b"again").unwrap();
+
+    // Send one end of a channel over another
+    let (inner_tx, inner_rx) = wasmcorelib::channel(1);
+    assert!(matches!(
+        tx.try_send_with(b"", alloc::vec![inner_rx.into_handle()]),
+        Err(wasmcorelib::SendError::Full)
+    ));
+    assert!(rx.try_recv().unwrap().bytes == b"world");
+    assert!(rx.try_recv().unwrap().bytes == b"again");
+    let (_, inner_rx) = wasmcorelib::channel(1);
+    tx.try_send_with(
+        b"",
+        alloc::vec![inner_tx.into_handle(), inner_rx.into_handle()],
+    )
+    .unwrap();
+    let mut msg = rx.try_recv().unwrap();
+    let mut inner_rx = msg.handles.pop().unwrap().into_receiver();
+    let mut inner_tx = msg.handles.pop().unwrap().into_sender();
+    // The first inner receiver went with the failed send
+    assert!(matches!(
+        inner_tx.try_send(b"lost"),
+        Err(wasmcorelib::SendError::Closed)
+    ));
+    drop(inner_tx);
+    assert!(matches!(
+        inner_rx.try_recv(),
+        Err(wasmcorelib::RecvError::Closed)
+    ));
+
+    // And between processes
+    let mut echo = wasmcorelib::create(echo_bytecode).unwrap().spawn().unwrap();
+    let (mut to_echo, from_us) = wasmcorelib::channel(1);
+    let (to_us, mut from_echo) = wasmcorelib::channel(1);
+    let from_us = from_us.into_handle().give(&echo).unwrap();
+    let to_us = to_us.into_handle().give(&echo).unwrap();
+
+    to_echo.try_send(b"ping").unwrap();
+    assert!(echo.invoke::<i32>("echo", params!(from_us, to_us)).unwrap() == 0);
+    assert!(from_echo.try_recv().unwrap().bytes == b"ping");
+
+    // Its ends go with it
+    echo.kill().unwrap();
+    assert!(matches!(
+        to_echo.try_send(b"ping"),
+        Err(wasmcorelib::SendError::Closed)
+    ));
+    assert!(matches!(
+        from_echo.try_recv(),
+        Err(wasmcorelib::RecvError::Closed)
+    ));
+
+    let mut shared = wasmcorelib::SharedMemory::new(1, Some(1)).unwrap();
+    let mut sharers = alloc::vec::Vec::new();
+    for _ in 0..2 {
+        let mut proc = wasmcorelib::create(shared_bytecode).unwrap();
+        proc.bind_service("log", "log").unwrap();
+        proc.bind_memory("shared", &shared).unwrap();
+        sharers.push(proc.spawn().unwrap());
+    }
+
+    // One writes, the other sees it
+    sharers[0]
+        .invoke::<()>("fill", params!(0_u32, 10_u32, 7_u32))
+        .unwrap();
+    assert!(
+        sharers[1]
+            .invoke::<i32>("sum", params!(0_u32, 10_u32))
+            .unwrap()
+            == 70
+    );
+
+    let mut buf = [0; 10];
+    shared.read(0, &mut buf).unwrap();
+    assert!(buf == [7; 10]);
+
+    // It's the child's only memory, so its syscalls use it too
+    let msg = b"hello from shared memory";
+    shared.write(100, msg).unwrap();
+    sharers[1]
+        .invoke::<()>("shout", params!(100_u32, msg.len() as u32))
+        .unwrap();
+
+    assert!(matches!(
+        shared.read(65536 - 5, &mut buf),
+        Err(wasmcorelib::MemoryError::OutOfBounds)
+    ));
+
+    // Outlives our handle, the children still have it
+    drop(shared);
+    assert!(
+        sharers[1]
+            .invoke::<i32>("sum", params!(0_u32, 10_u32))
+            .unwrap()
+            == 70
+    );
+    drop(sharers);
+
+    let tiny = wasmcorelib::SharedMemory::new(0, Some(0)).unwrap();
+    let mut proc = wasmcorelib::create(shared_bytecode).unwrap();
+    proc.bind_service("log", "log").unwrap();
+    proc.bind_memory("shared", &tiny).unwrap();
+    assert!(matches!(
+        proc.spawn(),
+        Err(wasmcorelib::SpawnError::InstantiationFailed)
+    ));
+
+    // The host makes a memory and table if nobody binds them
+    let mut first = wasmcorelib::create(globals_bytecode)
+        .unwrap()
+        .spawn()
+        .unwrap();
+    let mut second = wasmcorelib::create(globals_bytecode)
+        .unwrap()
+        .spawn()
+        .unwrap();
+    assert!(first.invoke::<i32>("abi", params!()).unwrap() == 2);
+    assert!(first.invoke::<i32>("pages", params!()).unwrap() == 2);
+    let pid = first.invoke::<i32>("pid", params!()).unwrap();
+    assert!(pid != 0 && pid != second.invoke::<i32>("pid", params!()).unwrap());
+    drop((first, second));
+
+    assert!(matches!(
+        wasmcorelib::create(greedy_bytecode).unwrap().spawn(),
+        Err(wasmcorelib::SpawnError::TooLarge)
+    ));
+
+    let limits = wasmcorelib::Limits {
+        memory_pages: 3,
+        table_elements: 0,
+        module_size: 4,
+        call_depth: 4,
+        value_stack: 100,
+    };
+    let mut limited = wasmcorelib::create(limited_bytecode).unwrap();
+    assert!(matches!(
+        limited.set_limits(wasmcorelib::Limits {
+            memory_pages: 1 << 20,
+            ..limits
+        }),
+        Err(wasmcorelib::SetLimitsError::LimitExceeded)
+    ));
+    limited.set_limits(limits).unwrap();
+    let mut limited = limited.spawn().unwrap();
+    assert!(limited.invoke::<i32>("grow", params!(2_u32)).unwrap() == 1);
+    assert!(limited.invoke::<i32>("grow", params!(1_u32)).unwrap() == -1);
+    assert!(
+        limited.invoke::<i32>("create", params!()).unwrap()
+            == wasmcorelib::ErrorCode::LimitExceeded as i32
+    );
+    drop(limited);
+
+    let mut roomier = wasmcorelib::create(limited_bytecode).unwrap();
+    roomier
+        .set_limits(wasmcorelib::Limits {
+            module_size: 8,
+            ..limits
+        })
+        .unwrap();
+    assert!(
+        roomier
+            .spawn()
+            .unwrap()
+            .invoke::<i32>("create", params!())
+            .unwrap()
+            == 0
+    );
+
+    let mut cramped = wasmcorelib::create(limited_bytecode).unwrap();
+    cramped
+        .set_limits(wasmcorelib::Limits {
+            memory_pages: 0,
+            ..limits
+        })
+        .unwrap();
+    assert!(matches!(
+        cramped.spawn(),
+        Err(wasmcorelib::SpawnError::LimitExceeded)
+    ));
+
+    // a shared memory its sharers could grow past its limit
+    let roomy = wasmcorelib::SharedMemory::new(1, None).unwrap();
+    let mut cramped = wasmcorelib::create(shared_bytecode).unwrap();
+    cramped.bind_service("log", "log").unwrap();
+    cramped.bind_memory("shared", &roomy).unwrap();
+    cramped.set_limits(limits).unwrap();
+    assert!(matches!(
+        cramped.spawn(),
+        Err(wasmcorelib::SpawnError::LimitExceeded)
+    ));
+
+    // Returns what recurse returned, or the error code if invoking it failed.
+    fn again(pid: i32, n: i32) -> i32 {
+        let name = "recurse";
+        let arg = n as u32 as u64;
+        let arg_type = b'i';
+        let mut result = 0;
+
+        let code = unsafe {
+            _invoke(
+                pid as u32,
+                name.as_ptr(),
+                name.len() as u32,
+                &arg,
+                &arg_type,
+                1,
+                &mut result,
+                core::ptr::null_mut(),
+                core::ptr::null_mut(),
+                0,
+            )
+        };
+
+        if code == 0 {
+            result as i32
+        } else {
+            code as i32
+        }
+    }
+
+    let stack_overflow = wasmcorelib::ErrorCode::StackOverflow as i32;
+
+    let mut recursive = wasmcorelib::create(recursive_bytecode).unwrap();
+    recursive
+        .bind("again", again as fn(i32, i32) -> i32)
+        .unwrap();
+    recursive.set_limits(limits).unwrap();
+    let mut recursive = recursive.spawn().unwrap();
+    assert!(recursive.invoke::<i32>("recurse", params!(3_u32)).unwrap() == 0);
+    assert!(recursive.invoke::<i32>("recurse", params!(4_u32)).unwrap() == stack_overflow);
+    assert!(recursive.invoke::<i32>("deep", params!(10_u32)).unwrap() == 10);
+    assert!(matches!(
+        recursive.invoke::<i32>("deep", params!(1000_u32)),
+        Err(wasmcorelib::InvokeError::StackOverflow)
+    ));
+    // and it's not left thinking the calls that overflowed are still on its stack
+    assert!(recursive.invoke::<i32>("deep", params!(10_u32)).unwrap() == 10);
+    drop(recursive);
+
+    // The host stops it eventually, even with no limits of its own
+    let mut recursive = wasmcorelib::create(recursive_bytecode).unwrap();
+    recursive
+        .bind("again", again as fn(i32, i32) -> i32)
+        .unwrap();
+    let mut recursive = recursive.spawn().unwrap();
+    assert!(
+        recursive
+            .invoke::<i32>("recurse", params!(100_000_u32))
+            .unwrap()
+            == stack_overflow
+    );
+    drop(recursive);
+
+    let mut trapping = wasmcorelib::create(trapping_bytecode)
+        .unwrap()
+        .spawn()
+        .unwrap();
+    assert!(matches!(
+        trapping.invoke::<()>("panic", params!()),
+        Err(wasmcorelib::InvokeError::Unreachable)
+    ));
+    assert!(matches!(
+        trapping.invoke::<i32>("load", params!()),
+        Err(wasmcorelib::InvokeError::AccessOutOfBounds)
+    ));
+    assert!(matches!(
+        trapping.invoke::<i32>("divide", params!(1_i32, 0_i32)),
+        Err(wasmcorelib::InvokeError::DivisionByZero)
+    ));
+    assert!(matches!(
+        trapping.invoke::<i32>("divide", params!(i32::MIN, -1_i32)),
+        Err(wasmcorelib::InvokeError::IntegerOverflow)
+    ));
+    assert!(
+        trapping
+            .invoke::<i32>("divide", params!(6_i32, 3_i32))
+            .unwrap()
+            == 2
+    );
+    assert!(matches!(
+        trapping.invoke::<i32>("truncate", params!(1e10_f32)),
+        Err(wasmcorelib::InvokeError::IntegerOverflow)
+    ));
+    assert!(matches!(
+        trapping.invoke::<i32>("truncate", params!(f32::NAN)),
+        Err(wasmcorelib::InvokeError::IntegerOverflow)
+    ));
+    assert!(
+        trapping
+            .invoke::<i32>("truncate", params!(-2.5_f32))
+            .unwrap()
+            == -2
+    );
+    // empty, mistyped, and past the end of the table
+    assert!(matches!(
+        trapping.invoke::<()>("indirect", params!(0_i32)),
+        Err(wasmcorelib::InvokeError::AccessOutOfBounds)
+    ));
+    assert!(matches!(
+        trapping.invoke::<()>("indirect", params!(1_i32)),
+        Err(wasmcorelib::InvokeError::IndirectCallMismatch)
+    ));
+    assert!(matches!(
+        trapping.invoke::<()>("indirect", params!(2_i32)),
+        Err(wasmcorelib::InvokeError::AccessOutOfBounds)
+    ));
+    let job = trapping.invoke_async::<()>("panic", params!()).unwrap();
+    assert!(matches!(
+        job.wait(),
+        Err(wasmcorelib::InvokeError::Unreachable)
+    ));
+    // A trap only ends the call, not the process.
+    assert!(trapping.invoke::<i32>("ok", params!()).unwrap() == 1337);
+    drop(trapping);
+
+    let mut panicking = wasmcorelib::create(panicking_bytecode)
+        .unwrap()
+        .spawn()
+        .unwrap();
+    let panicked = panicking.invoke::<()>("panic", params!());
+    assert!(matches!(
+        panicking.invoke::<()>("garbled", params!()),
+        Err(wasmcorelib::InvokeError::Panicked { message }) if message.is_empty()
+    ));
+    let job = panicking.invoke_async::<()>("panic", params!()).unwrap();
+    assert!(matches!(
+        job.wait(),
+        Err(wasmcorelib::InvokeError::Panicked { message }) if message == "oh no at src/lib.rs:1:1"
+    ));
+    // What it said is ours once the call's failed, even after the process is gone.
+    drop(panicking);
+    assert!(matches!(
+        panicked,
+        Err(wasmcorelib::InvokeError::Panicked { message }) if message == "oh no at src/lib.rs:1:1"
+    ));
+
+    // A buffer that's too small gets as much as fits, and the result says how long it was.
+    unsafe {
+        let handle = _spawn(
+            _create(
+                panicking_bytecode.as_ptr(),
+                panicking_bytecode.len() as u32,
+                core::ptr::null_mut(),
+            ),
+            core::ptr::null_mut(),
+        );
+        let name = "panic";
+        let mut length: u64 = 0;
+        let mut message = [0u8; 5];
+
+        let code = _invoke(
+            handle,
+            name.as_ptr(),
+            name.len() as u32,
+            core::ptr::null(),
+            core::ptr::null(),
+            0,
+            &mut length,
+            core::ptr::null_mut(),
+            message.as_mut_ptr(),
+            message.len() as u32,
+        );
+
+        assert!(code == wasmcorelib::ErrorCode::Panicked as u32);
+        assert!(&message == b"oh no");
+        assert!(length == 23);
+        assert!(_kill(handle) == 0);
+    }
+
+    println!(
+        "hello from {}, {} + {} = {}",
+        "the root process",
+        132,
+        205,
+        132 + 205
+    );
+    eprintln!("and hello to stderr too");
+    let msg = "not a real fd";
+    assert!(
+        unsafe { _write(3, msg.as_ptr(), msg.len() as u32) }
+            == wasmcorelib::ErrorCode::InvalidHandle as u32
+    );
+
+    wasmcorelib::marker("pragmas");
+    wasmcorelib::log(wasmcorelib::LogLevel::Info, "a structured log message");
+    wasmcorelib::log(wasmcorelib::LogLevel::Trace, "filtered out by default");
+    wasmcorelib::breakpoint();
+    // Pragmas are only hints, so nonsense ones are just ignored.
+    unsafe {
+        wasmcorelib::_pragma(1, 0xffff_fff0 as *const u8);
+        wasmcorelib::_pragma(12345, core::ptr::null());
+    }
+
+    assert!(matches!(
+        wasmcorelib::create(b"not wasm"),
+        Err(wasmcorelib::CreateProcessError::Malformed)
+    ));
+    assert!(matches!(
+        wasmcorelib::create(b"(module (func (result i32)"),
+        Err(wasmcorelib::CreateProcessError::Malformed)
+    ));
+    // Parses fine, but returns nothing when it says it returns an i32
+    assert!(matches!(
+        wasmcorelib::create(b"(module (func (result i32)))"),
+        Err(wasmcorelib::CreateProcessError::ValidationFailed)
+    ));
 
     1337
 }
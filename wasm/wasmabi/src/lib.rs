@@ -0,0 +1,294 @@
+#![no_std]
+
+// Things both sides of the syscall boundary need to agree on. The host links this in as a normal
+// crate, wasmcorelib re-exports it for guests.
+
+/// Status codes returned by syscalls. 0 is always success, so it's not a variant here.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The handle doesn't refer to anything the host knows about (or it's the wrong kind of
+    /// handle, like passing a spawned process to `_bind`).
+    InvalidHandle = 1,
+    /// A pointer/length pair went outside of the caller's memory.
+    OutOfBounds = 2,
+    /// A name wasn't valid UTF-8.
+    InvalidUtf8 = 3,
+    /// `_bind` was given something that isn't a function in the caller's table.
+    InvalidFunction = 4,
+    /// The child imports a function that nobody bound.
+    MissingImport = 5,
+    /// The child's module couldn't be instantiated with the bindings it was given (usually a
+    /// bound function's signature doesn't match the import).
+    InstantiationFailed = 6,
+    /// `_invoke` asked for an export that doesn't exist.
+    NoSuchExport = 7,
+    /// `_invoke` asked for an export that exists but isn't a function.
+    NotAFunction = 8,
+    /// The argument types passed to `_invoke` don't match the function's signature (or one of
+    /// them isn't a type at all).
+    TypeMismatch = 9,
+    /// `_create` was given something that doesn't decode as a wasm module.
+    Malformed = 10,
+    /// `_create` was given a module that decodes, but doesn't validate.
+    ValidationFailed = 11,
+    /// `_create` was given a module that uses a wasm feature (or version) we don't support.
+    UnsupportedFeature = 12,
+    /// `_create` was given more bytecode than the host is willing to load, `_recv` was given too
+    /// little space for a message, or a process wants a bigger memory or table than the host
+    /// will give it.
+    TooLarge = 13,
+    /// Asked for an import or export by an index past the end.
+    NoSuchItem = 14,
+    /// `_kill` was asked to kill a process that's running right now (further up the call stack),
+    /// or that spawned one that is. Or a call was waited on that can't start until one like that
+    /// finishes.
+    ProcessBusy = 15,
+    /// `_bind_service` was asked for a service the host doesn't provide.
+    NoSuchService = 16,
+    /// A buffer couldn't be allocated in a process, either because it doesn't export memory and
+    /// `ALLOC_EXPORT`, or because that failed.
+    AllocationFailed = 17,
+    /// `_invoke` was passed a different number of arguments than the function takes.
+    ArityMismatch = 18,
+    /// The process used up the fuel it was given with `_set_fuel` before the function returned.
+    OutOfFuel = 19,
+    /// `_poll` was asked about a job that hasn't finished yet.
+    Pending = 20,
+    /// `_send` found the channel's queue full.
+    ChannelFull = 21,
+    /// `_recv` found nothing to receive.
+    ChannelEmpty = 22,
+    /// The other end of the channel has been closed (and, for `_recv`, there's nothing left in
+    /// it).
+    ChannelClosed = 23,
+    /// Something went over a limit its process's parent set with `_set_limits`, or `_set_limits`
+    /// tried to give a process more than the caller has itself.
+    LimitExceeded = 24,
+    /// Calls into processes were nested deeper than the host or one of the processes allows, or
+    /// a process's own code went over its value stack limit.
+    StackOverflow = 25,
+    /// The invoked code hit an `unreachable` instruction.
+    Unreachable = 26,
+    /// The invoked code accessed memory or a table out of bounds, or called an empty table entry.
+    AccessOutOfBounds = 27,
+    /// The invoked code divided by zero, or took a remainder of it.
+    DivisionByZero = 28,
+    /// The invoked code trapped in a host function for a reason with no code of its own, or the
+    /// host couldn't run it at all.
+    HostError = 29,
+    /// The invoked code called `_panic`, or called into something that did. What it said is
+    /// written out along with the error.
+    Panicked = 30,
+    /// The invoked code got an integer result too big to represent: by dividing the most negative
+    /// signed integer by -1, or by truncating a float that's out of range (or NaN) to an integer.
+    IntegerOverflow = 31,
+    /// The invoked code made a `call_indirect` to a function with a different type than it said.
+    IndirectCallMismatch = 32,
+}
+
+impl ErrorCode {
+    pub fn from_u32(code: u32) -> Option<Self> {
+        use ErrorCode::*;
+
+        Some(match code {
+            1 => InvalidHandle,
+            2 => OutOfBounds,
+            3 => InvalidUtf8,
+            4 => InvalidFunction,
+            5 => MissingImport,
+            6 => InstantiationFailed,
+            7 => NoSuchExport,
+            8 => NotAFunction,
+            9 => TypeMismatch,
+            10 => Malformed,
+            11 => ValidationFailed,
+            12 => UnsupportedFeature,
+            13 => TooLarge,
+            14 => NoSuchItem,
+            15 => ProcessBusy,
+            16 => NoSuchService,
+            17 => AllocationFailed,
+            18 => ArityMismatch,
+            19 => OutOfFuel,
+            20 => Pending,
+            21 => ChannelFull,
+            22 => ChannelEmpty,
+            23 => ChannelClosed,
+            24 => LimitExceeded,
+            25 => StackOverflow,
+            26 => Unreachable,
+            27 => AccessOutOfBounds,
+            28 => DivisionByZero,
+            29 => HostError,
+            30 => Panicked,
+            31 => IntegerOverflow,
+            32 => IndirectCallMismatch,
+            _ => return None,
+        })
+    }
+}
+
+// Type tags for wasm value types, as used by `_invoke`'s argument types and in descriptors.
+pub const TYPE_I32: u8 = b'i';
+pub const TYPE_I64: u8 = b'I';
+pub const TYPE_F32: u8 = b'f';
+pub const TYPE_F64: u8 = b'F';
+/// For where there's no type, like the result of a function that doesn't return anything.
+pub const TYPE_NONE: u8 = 0;
+/// Only for `_invoke` arguments. The value is a pointer into the caller's memory in the low 32
+/// bits and a length in the high 32. The bytes get copied into the callee's memory, and the
+/// function gets the (pointer, length) of the copy as two i32s.
+pub const TYPE_BUFFER: u8 = b'b';
+
+/// What a process exports to have buffers passed to it. Takes a length, returns a pointer to that
+/// many bytes (which the process then owns), or 0 if it couldn't.
+pub const ALLOC_EXPORT: &str = "__wasmos_alloc";
+/// What a process exports to have buffers it returned given back to it. Takes a pointer and a
+/// length. The host calls it too, for buffers it allocated for a call that then failed.
+pub const FREE_EXPORT: &str = "__wasmos_free";
+
+/// `_pragma` codes 1 to 5 log a message at that level. Their value points at a (ptr: u32,
+/// len: u32) pair for the UTF-8 message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl LogLevel {
+    pub fn from_u32(code: u32) -> Option<Self> {
+        use LogLevel::*;
+
+        Some(match code {
+            1 => Error,
+            2 => Warn,
+            3 => Info,
+            4 => Debug,
+            5 => Trace,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+/// `_pragma` code for somewhere a debugger might want to stop. The value is ignored.
+pub const PRAGMA_BREAKPOINT: u32 = 16;
+/// `_pragma` code for marking a point in the log. The value points at a (ptr: u32, len: u32) pair
+/// for its name.
+pub const PRAGMA_MARKER: u32 = 17;
+
+/// The most of a panic message that gets passed on. Anything past it is cut off.
+pub const MAX_PANIC_MESSAGE: u32 = 4096;
+
+/// File descriptors `_write` takes, mapped to the host's own.
+pub const STDOUT: u32 = 1;
+pub const STDERR: u32 = 2;
+
+/// Bumped whenever syscalls change in a way that'd break existing processes.
+pub const ABI_VERSION: u32 = 2;
+
+/// Immutable i32 globals any process can import from `env`. Its own handle (as its parent sees
+/// it), and `ABI_VERSION`.
+pub const PID_GLOBAL: &str = "__pid";
+pub const ABI_VERSION_GLOBAL: &str = "__abi_version";
+
+/// What an import or export is.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemKind {
+    Function = 0,
+    Table = 1,
+    Memory = 2,
+    Global = 3,
+}
+
+impl ItemKind {
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        use ItemKind::*;
+
+        Some(match kind {
+            0 => Function,
+            1 => Table,
+            2 => Memory,
+            3 => Global,
+            _ => return None,
+        })
+    }
+}
+
+/// An import or export of a module, as written out by the `_*_describe` syscalls.
+///
+/// On the wire, it's the kind as one byte, then `module`, `name` and `params` each as a little
+/// endian u32 length followed by that many bytes, then `result` as one byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Descriptor<'a> {
+    pub kind: ItemKind,
+    /// The module an import comes from. Always empty for exports.
+    pub module: &'a str,
+    pub name: &'a str,
+    /// Type tags of the parameters, if this is a function.
+    pub params: &'a [u8],
+    /// Type tag of what a function returns or a global holds, or `TYPE_NONE`.
+    pub result: u8,
+}
+
+impl<'a> Descriptor<'a> {
+    pub fn encoded_len(&self) -> usize {
+        1 + 4 + self.module.len() + 4 + self.name.len() + 4 + self.params.len() + 1
+    }
+
+    /// Writes the descriptor into `buf`, which must be at least `encoded_len()` long.
+    pub fn encode(&self, buf: &mut [u8]) {
+        fn put<'b>(buf: &'b mut [u8], bytes: &[u8]) -> &'b mut [u8] {
+            buf[..4].copy_from_slice(&(bytes.len() as u32).to_le_bytes());
+            buf[4..4 + bytes.len()].copy_from_slice(bytes);
+            &mut buf[4 + bytes.len()..]
+        }
+
+        buf[0] = self.kind as u8;
+        let rest = put(&mut buf[1..], self.module.as_bytes());
+        let rest = put(rest, self.name.as_bytes());
+        let rest = put(rest, self.params);
+        rest[0] = self.result;
+    }
+
+    /// Reads a descriptor back out. Returns `None` if it's not a valid encoding.
+    pub fn decode(buf: &'a [u8]) -> Option<Self> {
+        fn take(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+            let mut len = [0; 4];
+            len.copy_from_slice(buf.get(..4)?);
+            let len = u32::from_le_bytes(len) as usize;
+
+            let bytes = buf.get(4..4 + len)?;
+            Some((bytes, &buf[4 + len..]))
+        }
+
+        let (&kind, rest) = buf.split_first()?;
+        let (module, rest) = take(rest)?;
+        let (name, rest) = take(rest)?;
+        let (params, rest) = take(rest)?;
+        let (&result, _) = rest.split_first()?;
+
+        Some(Descriptor {
+            kind: ItemKind::from_u8(kind)?,
+            module: core::str::from_utf8(module).ok()?,
+            name: core::str::from_utf8(name).ok()?,
+            params,
+            result,
+        })
+    }
+}
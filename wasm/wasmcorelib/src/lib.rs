@@ -4,26 +4,75 @@
 use core::convert::TryInto;
 extern crate alloc;
 
+pub use wasmabi::{ErrorCode, LogLevel};
+pub use wasmcorelib_derive::IntoParams;
+
 extern "C" {
     // Hint. Used for debugging. Will never cause side effects, must act as if it's defined as a
     // no-op.
     //
-    // But can be used for debug logs... and any other ignorable hints.
+    // But can be used for debug logs... and any other ignorable hints. See wasmabi::LogLevel,
+    // PRAGMA_BREAKPOINT and PRAGMA_MARKER for the ones the host understands.
     pub fn _pragma(val: u32, value: *const u8);
 
-    // Creates a process using the wasm bytecode. Would write a result into result if I implemented
-    // that yet...
+    // Creates a process using the wasm bytecode, which can be in the text format too. Writes 0 or
+    // an ErrorCode into result (if it's not null), and returns 0 as the handle on failure.
     pub fn _create(bytecode: *const u8, bytecode_length: u32, result: *mut u32) -> u32; // handle to create process
 
     // Binds a function by the name fn_name to the function func
     // func must be in the table so that we can pass it to the new process
+    //
+    // Returns 0 on success, or an ErrorCode.
     pub fn _bind(handle: u32, fn_name: *const u8, fn_name_length: u32, func: *const u8) -> u32;
 
+    // Like _bind, but func gets env as an extra first argument whenever the child calls it (the
+    // child doesn't see it). Meant for closures, env being a pointer to what they captured.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _bind_env(
+        handle: u32,
+        fn_name: *const u8,
+        fn_name_length: u32,
+        func: *const u8,
+        env: *const u8,
+    ) -> u32;
+
+    // Binds fn_name straight to something the host provides, like "log" or "clock". It runs as
+    // whoever calls it, so the child doesn't have to go through us.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _bind_service(
+        handle: u32,
+        fn_name: *const u8,
+        fn_name_length: u32,
+        service: *const u8,
+        service_length: u32,
+    ) -> u32;
+
+    // Binds fn_name to an exported function of target, a process we spawned. It runs as target.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _bind_export(
+        handle: u32,
+        fn_name: *const u8,
+        fn_name_length: u32,
+        target: u32,
+        export: *const u8,
+        export_length: u32,
+    ) -> u32;
+
     // Actually creates a moduleinstance from the process. Returns a *new* handle type of *spawned
-    // process*.
-    pub fn _spawn(handle: u32) -> u32;
+    // process*. Writes 0 or an ErrorCode into result (if it's not null).
+    //
+    // The process handle is used up either way.
+    pub fn _spawn(handle: u32, result: *mut u32) -> u32;
 
-    // Invokes a specific function on a spawned process.
+    // Invokes a specific function on a spawned process. Writes what it returned into result
+    // (zero extended to 8 bytes) and its type tag into result_type, TYPE_NONE if it didn't return
+    // anything. If it panicked, writes as much of what it said as fits into panic_message instead,
+    // and how long it is into result. Any of them can be null.
+    //
+    // Returns 0 on success, or an ErrorCode.
     pub fn _invoke(
         handle: u32,
         fn_name: *const u8,
@@ -32,20 +81,229 @@ extern "C" {
         argtypes_ptr: *const u8,
         arglen: u32,
         result: *mut u64,
+        result_type: *mut u8,
+        panic_message: *mut u8,
+        panic_message_length: u32,
+    ) -> u32;
+
+    // Like _invoke, but queues the call up and returns a job handle straight away instead of
+    // waiting for it. Writes 0 or an ErrorCode into result (if it's not null), and returns 0 as
+    // the handle on failure. The arguments are checked (and buffers copied over) up front.
+    pub fn _invoke_async(
+        handle: u32,
+        fn_name: *const u8,
+        fn_name_length: u32,
+        arguments_ptr: *const u64,
+        argtypes_ptr: *const u8,
+        arglen: u32,
+        result: *mut u32,
+    ) -> u32;
+
+    // Lets the scheduler run a bit, then checks on a job. If it's done, writes its result like
+    // _invoke does and the job handle is used up.
+    //
+    // Returns 0 on success, ErrorCode::Pending if it hasn't finished yet, or another ErrorCode.
+    pub fn _poll(
+        job: u32,
+        result: *mut u64,
+        result_type: *mut u8,
+        panic_message: *mut u8,
+        panic_message_length: u32,
+    ) -> u32;
+
+    // Runs everything that's queued until the job is done, then writes its result like _invoke
+    // does. The job handle is used up.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _wait(
+        job: u32,
+        result: *mut u64,
+        result_type: *mut u8,
+        panic_message: *mut u8,
+        panic_message_length: u32,
+    ) -> u32;
+
+    // Throws away a job we don't want the result of anymore. If it hasn't finished, it never will.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _cancel(job: u32) -> u32;
+
+    // Creates a channel that can hold up to capacity messages at once. Writes handles to its two
+    // ends into sender and receiver. Both get closed with _close.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _channel_create(capacity: u32, sender: *mut u32, receiver: *mut u32) -> u32;
+
+    // Sends a message of bytes, along with the ends of some other channels, which we don't own
+    // anymore if it works. Doesn't wait if the channel's full.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _send(
+        handle: u32,
+        bytes: *const u8,
+        bytes_length: u32,
+        handles: *const u32,
+        handles_length: u32,
+    ) -> u32;
+
+    // Receives a message, if there is one. Writes how long it is and how many handles came with
+    // it into length and handles_length (if they're not null). If it doesn't fit in buffer and
+    // handles, it stays in the channel and this returns ErrorCode::TooLarge.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _recv(
+        handle: u32,
+        buffer: *mut u8,
+        buffer_length: u32,
+        length: *mut u32,
+        handles: *mut u32,
+        handles_capacity: u32,
+        handles_length: *mut u32,
+    ) -> u32;
+
+    // Gives the end of a channel to a process we spawned. The handle stays the same, so pass it to
+    // them somehow.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _give(handle: u32, process: u32) -> u32;
+
+    // Creates a memory that can be bound to any number of processes, pages * 64KiB big to start
+    // with. max_pages is capped at our memory limit, and a negative one means it can grow as far
+    // as that. Writes 0 or an ErrorCode into result (if it's not null), and returns 0 as the
+    // handle on failure. Closed with _close, but processes it's bound to keep it alive.
+    pub fn _memory_create(pages: u32, max_pages: i32, result: *mut u32) -> u32;
+
+    // Binds the memory import name to a shared memory. Only one memory per module, so the child
+    // can't have one of its own as well.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _bind_memory(handle: u32, name: *const u8, name_length: u32, memory: u32) -> u32;
+
+    // Copy len bytes between offset in a shared memory and ptr in ours.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _memory_read(memory: u32, offset: u32, dst: *mut u8, len: u32) -> u32;
+    pub fn _memory_write(memory: u32, offset: u32, src: *const u8, len: u32) -> u32;
+
+    // Writes len bytes to one of the host's file descriptors, wasmabi::STDOUT or wasmabi::STDERR.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _write(fd: u32, ptr: *const u8, len: u32) -> u32;
+
+    // Ends the call we're in with a trap, passing message on to whoever made the call. It doesn't
+    // have to be valid UTF-8, and only the first wasmabi::MAX_PANIC_MESSAGE bytes of it are kept.
+    // Never returns.
+    pub fn _panic(message: *const u8, message_length: u32);
+
+    // Limits how many more instructions a process we spawned can run, counting everything it runs
+    // from now on. Negative means no limit, which is what it starts with. Once it's out, invoking
+    // it fails.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _set_fuel(handle: u32, fuel: i64) -> u32;
+
+    // Limits what a process we created (but haven't spawned yet) can use: how many pages any
+    // memory it has can grow to, how many elements its table can grow to, the biggest module it
+    // can _create, how many calls into it can be nested at once, and how many values its code can
+    // have on its stack. It starts out with ours, and can't be given more than that.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _set_limits(
+        handle: u32,
+        memory_pages: u32,
+        table_elements: u32,
+        module_size: u32,
+        call_depth: u32,
+        value_stack: u32,
+    ) -> u32;
+
+    // Copies len bytes from src in the memory of a process we spawned to dst in ours. For getting
+    // back buffers that _invoke returned pointers to.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _read_memory(handle: u32, src: *const u8, len: u32, dst: *mut u8) -> u32;
+
+    // Introspection, so you can know what some bytecode wants/exports before you bind and spawn
+    // it. These work on created and spawned processes.
+    //
+    // The counts return how many imports/exports there are. The describes write a
+    // wasmabi::Descriptor for the one at index into buf if it fits in buf_length bytes, and return
+    // how long it is either way. All of them write 0 or an ErrorCode into result.
+    pub fn _import_count(handle: u32, result: *mut u32) -> u32;
+    pub fn _import_describe(
+        handle: u32,
+        index: u32,
+        buf: *mut u8,
+        buf_length: u32,
+        result: *mut u32,
     ) -> u32;
+    pub fn _export_count(handle: u32, result: *mut u32) -> u32;
+    pub fn _export_describe(
+        handle: u32,
+        index: u32,
+        buf: *mut u8,
+        buf_length: u32,
+        result: *mut u32,
+    ) -> u32;
+
+    // Kills a spawned process, along with everything it spawned or created. Fails if any of them
+    // are running (i.e. somewhere up the call stack from us).
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _kill(handle: u32) -> u32;
+
+    // Throws away a created process without spawning it, or closes the end of a channel.
+    //
+    // Returns 0 on success, or an ErrorCode.
+    pub fn _close(handle: u32) -> u32;
+}
+
+/// What closures bound into a process captured. Has to live as long as the process does.
+type Envs = alloc::vec::Vec<alloc::boxed::Box<dyn core::any::Any>>;
 
-// todo: introspection APIs so you can know what some bytecode wants/exports
+pub struct CreateProcessHandle {
+    handle: u32,
+    envs: Envs,
 }
 
-pub struct CreateProcessHandle(u32);
+impl Drop for CreateProcessHandle {
+    fn drop(&mut self) {
+        unsafe {
+            _close(self.handle);
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum CreateProcessError {
     /// Tried to create a process with a bytecode length over 4GB (won't fit in a u32)
     TooLong,
+    /// The host won't load modules this big.
+    TooLarge,
+    /// The bytecode doesn't decode as a wasm module, or parse as one in the text format.
+    Malformed,
+    /// The bytecode decodes, but isn't a valid module.
+    ValidationFailed,
+    /// The module uses a wasm feature (or version) that the host doesn't support.
+    UnsupportedFeature,
+    /// The module is bigger than our parent lets us create.
+    LimitExceeded,
     Unknown(u32),
 }
 
+impl CreateProcessError {
+    fn from_code(code: u32) -> Self {
+        match ErrorCode::from_u32(code) {
+            Some(ErrorCode::TooLarge) => CreateProcessError::TooLarge,
+            Some(ErrorCode::Malformed) => CreateProcessError::Malformed,
+            Some(ErrorCode::ValidationFailed) => CreateProcessError::ValidationFailed,
+            Some(ErrorCode::UnsupportedFeature) => CreateProcessError::UnsupportedFeature,
+            Some(ErrorCode::LimitExceeded) => CreateProcessError::LimitExceeded,
+            _ => CreateProcessError::Unknown(code),
+        }
+    }
+}
+
 pub fn create(bytecode: &[u8]) -> Result<CreateProcessHandle, CreateProcessError> {
     unsafe {
         let mut err_code: u32 = 0;
@@ -59,172 +317,1664 @@ pub fn create(bytecode: &[u8]) -> Result<CreateProcessHandle, CreateProcessError
         );
 
         if err_code == 0 {
-            Ok(CreateProcessHandle(result))
+            Ok(CreateProcessHandle {
+                handle: result,
+                envs: Envs::new(),
+            })
         } else {
-            Err(CreateProcessError::Unknown(err_code))
+            Err(CreateProcessError::from_code(err_code))
         }
     }
 }
 
-pub unsafe trait IntoFnHandle {
-    fn into_handle(self) -> u32;
+#[derive(Debug)]
+pub enum IntrospectError {
+    InvalidHandle,
+    Unknown(u32),
 }
 
-pub unsafe trait Arg {}
+impl IntrospectError {
+    fn from_code(code: u32) -> Self {
+        match ErrorCode::from_u32(code) {
+            Some(ErrorCode::InvalidHandle) => IntrospectError::InvalidHandle,
+            _ => IntrospectError::Unknown(code),
+        }
+    }
+}
 
-unsafe impl Arg for f32 {}
-unsafe impl Arg for f64 {}
-unsafe impl Arg for i32 {}
-unsafe impl Arg for i64 {}
-unsafe impl Arg for u32 {}
-unsafe impl Arg for u64 {}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    I32,
+    I64,
+    F32,
+    F64,
+}
 
-unsafe impl IntoFnHandle for fn() {
-    fn into_handle(self) -> u32 {
-        unsafe { core::mem::transmute(self) }
+impl ValueType {
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            wasmabi::TYPE_I32 => Some(ValueType::I32),
+            wasmabi::TYPE_I64 => Some(ValueType::I64),
+            wasmabi::TYPE_F32 => Some(ValueType::F32),
+            wasmabi::TYPE_F64 => Some(ValueType::F64),
+            _ => None,
+        }
     }
 }
-unsafe impl<R: Arg> IntoFnHandle for fn() -> R {
-    fn into_handle(self) -> u32 {
-        unsafe { core::mem::transmute(self) }
+
+/// A value a function returned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl Value {
+    pub fn ty(&self) -> ValueType {
+        match self {
+            Value::I32(_) => ValueType::I32,
+            Value::I64(_) => ValueType::I64,
+            Value::F32(_) => ValueType::F32,
+            Value::F64(_) => ValueType::F64,
+        }
+    }
+
+    fn from_raw(tag: u8, bits: u64) -> Option<Self> {
+        match tag {
+            wasmabi::TYPE_I32 => Some(Value::I32(bits as u32 as i32)),
+            wasmabi::TYPE_I64 => Some(Value::I64(bits as i64)),
+            wasmabi::TYPE_F32 => Some(Value::F32(f32::from_bits(bits as u32))),
+            wasmabi::TYPE_F64 => Some(Value::F64(f64::from_bits(bits))),
+            _ => None,
+        }
     }
 }
 
-unsafe impl<T1: Arg> IntoFnHandle for fn(T1) {
-    fn into_handle(self) -> u32 {
-        unsafe { core::mem::transmute(self) }
+/// Something `ProcessHandle::invoke` can return, if the function returned the right type.
+pub trait FromResult: Sized {
+    fn from_result(value: Option<Value>) -> Option<Self>;
+}
+
+/// For functions that don't return anything.
+impl FromResult for () {
+    fn from_result(value: Option<Value>) -> Option<Self> {
+        match value {
+            None => Some(()),
+            Some(_) => None,
+        }
     }
 }
-unsafe impl<T1: Arg, R: Arg> IntoFnHandle for fn(T1) -> R {
-    fn into_handle(self) -> u32 {
-        unsafe { core::mem::transmute(self) }
+
+/// For when you don't know (or care) what it returns.
+impl FromResult for Option<Value> {
+    fn from_result(value: Option<Value>) -> Option<Self> {
+        Some(value)
     }
 }
 
-unsafe impl<T1: Arg, T2: Arg> IntoFnHandle for fn(T1, T2) {
-    fn into_handle(self) -> u32 {
-        unsafe { core::mem::transmute(self) }
+impl FromResult for Value {
+    fn from_result(value: Option<Value>) -> Option<Self> {
+        value
     }
 }
-unsafe impl<T1: Arg, T2: Arg, R: Arg> IntoFnHandle for fn(T1, T2) -> R {
-    fn into_handle(self) -> u32 {
-        unsafe { core::mem::transmute(self) }
+
+macro_rules! impl_from_result {
+    ( $( $ty:ty => $variant:ident ),* ) => {
+        $(
+            impl FromResult for $ty {
+                fn from_result(value: Option<Value>) -> Option<Self> {
+                    match value {
+                        Some(Value::$variant(v)) => Some(v as $ty),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_from_result!(i32 => I32, u32 => I32, i64 => I64, u64 => I64, f32 => F32, f64 => F64);
+
+/// What an import or export is, and its type if it has one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ItemType {
+    Function {
+        params: alloc::vec::Vec<ValueType>,
+        result: Option<ValueType>,
+    },
+    Table,
+    Memory,
+    Global(ValueType),
+}
+
+impl ItemType {
+    fn from_descriptor(desc: &wasmabi::Descriptor) -> Self {
+        let ty = |tag| ValueType::from_tag(tag).expect("host gave us an unknown type");
+
+        match desc.kind {
+            wasmabi::ItemKind::Function => ItemType::Function {
+                params: desc.params.iter().cloned().map(ty).collect(),
+                result: ValueType::from_tag(desc.result),
+            },
+            wasmabi::ItemKind::Table => ItemType::Table,
+            wasmabi::ItemKind::Memory => ItemType::Memory,
+            wasmabi::ItemKind::Global => ItemType::Global(ty(desc.result)),
+        }
     }
 }
 
-#[derive(Debug)]
-pub enum BindProcessError {
-    NameTooLong,
-    Unknown(u32),
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportDescriptor {
+    pub module: alloc::string::String,
+    pub field: alloc::string::String,
+    pub ty: ItemType,
 }
 
-impl CreateProcessHandle {
-    pub fn bind(&mut self, name: &str, to: impl IntoFnHandle) -> Result<(), BindProcessError> {
-        let result;
-        unsafe {
-            result = _bind(
-                self.0,
-                name.as_ptr(),
-                name.len()
-                    .try_into()
-                    .map_err(|_| BindProcessError::NameTooLong)?,
-                to.into_handle() as *const u8,
-            );
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportDescriptor {
+    pub name: alloc::string::String,
+    pub ty: ItemType,
+}
+
+type DescribeFn = unsafe extern "C" fn(u32, u32, *mut u8, u32, *mut u32) -> u32;
+
+/// Gets the raw descriptor of an import or export, growing the buffer until it fits.
+fn fetch_descriptor(
+    describe: DescribeFn,
+    handle: u32,
+    index: u32,
+) -> Result<alloc::vec::Vec<u8>, IntrospectError> {
+    let mut buf = alloc::vec![0; 64];
+
+    loop {
+        let mut err_code: u32 = 0;
+        let len = unsafe {
+            describe(
+                handle,
+                index,
+                buf.as_mut_ptr(),
+                buf.len() as u32,
+                &mut err_code as *mut u32,
+            )
+        } as usize;
+
+        if err_code != 0 {
+            return Err(IntrospectError::from_code(err_code));
         }
 
-        if result == 0 {
-            Ok(())
-        } else {
-            Err(BindProcessError::Unknown(result))
+        if len <= buf.len() {
+            buf.truncate(len);
+            return Ok(buf);
         }
+
+        buf.resize(len, 0);
     }
 }
 
-pub struct ProcessHandle(u32);
-#[derive(Debug)]
-pub enum SpawnError {}
+/// Iterator over the imports of a process.
+pub struct Imports<'a> {
+    handle: u32,
+    next: u32,
+    count: u32,
+    _process: core::marker::PhantomData<&'a ()>,
+}
 
-impl CreateProcessHandle {
-    pub fn spawn(self) -> Result<ProcessHandle, SpawnError> {
-        let new_handle;
+impl Iterator for Imports<'_> {
+    type Item = ImportDescriptor;
 
-        unsafe {
-            new_handle = _spawn(self.0);
+    fn next(&mut self) -> Option<ImportDescriptor> {
+        if self.next == self.count {
+            return None;
         }
 
-        Ok(ProcessHandle(new_handle))
+        let buf = fetch_descriptor(_import_describe, self.handle, self.next)
+            .expect("import disappeared while we were looking at it");
+        let desc = wasmabi::Descriptor::decode(&buf).expect("host gave us a bad descriptor");
+
+        self.next += 1;
+
+        Some(ImportDescriptor {
+            module: desc.module.into(),
+            field: desc.name.into(),
+            ty: ItemType::from_descriptor(&desc),
+        })
     }
 }
 
-#[macro_export]
-macro_rules! params {
-    ( $( $x:expr ),* ) => {
-        {
-            let mut temp_vec = alloc::vec::Vec::new();
-            let mut type_vec = alloc::vec::Vec::new();
-            $(
-                {
-                    use $crate::IntoParam;
-                    temp_vec.push($x.into_param());
-                    type_vec.push($x.paramtype());
-                }
-            )*
-            unsafe {
-                $crate::Params::new(temp_vec, type_vec)
-            }
+/// Iterator over the exports of a process.
+pub struct Exports<'a> {
+    handle: u32,
+    next: u32,
+    count: u32,
+    _process: core::marker::PhantomData<&'a ()>,
+}
+
+impl Iterator for Exports<'_> {
+    type Item = ExportDescriptor;
+
+    fn next(&mut self) -> Option<ExportDescriptor> {
+        if self.next == self.count {
+            return None;
         }
-    };
+
+        let buf = fetch_descriptor(_export_describe, self.handle, self.next)
+            .expect("export disappeared while we were looking at it");
+        let desc = wasmabi::Descriptor::decode(&buf).expect("host gave us a bad descriptor");
+
+        self.next += 1;
+
+        Some(ExportDescriptor {
+            name: desc.name.into(),
+            ty: ItemType::from_descriptor(&desc),
+        })
+    }
 }
 
-pub struct Params(alloc::vec::Vec<u64>, alloc::vec::Vec<u8>);
+fn imports_of<'a>(handle: u32) -> Result<Imports<'a>, IntrospectError> {
+    let mut err_code: u32 = 0;
+    let count = unsafe { _import_count(handle, &mut err_code as *mut u32) };
 
-impl Params {
-    pub unsafe fn new(values: alloc::vec::Vec<u64>, types: alloc::vec::Vec<u8>) -> Self {
-        Self(values, types)
+    if err_code != 0 {
+        return Err(IntrospectError::from_code(err_code));
     }
+
+    Ok(Imports {
+        handle,
+        next: 0,
+        count,
+        _process: core::marker::PhantomData,
+    })
 }
 
-pub trait IntoParam {
-    fn into_param(self) -> u64;
-    fn paramtype(self) -> u8;
+fn exports_of<'a>(handle: u32) -> Result<Exports<'a>, IntrospectError> {
+    let mut err_code: u32 = 0;
+    let count = unsafe { _export_count(handle, &mut err_code as *mut u32) };
+
+    if err_code != 0 {
+        return Err(IntrospectError::from_code(err_code));
+    }
+
+    Ok(Exports {
+        handle,
+        next: 0,
+        count,
+        _process: core::marker::PhantomData,
+    })
 }
 
-impl IntoParam for u32 {
-    fn into_param(self) -> u64 {
-        self as u64
+impl CreateProcessHandle {
+    pub fn imports(&self) -> Result<Imports<'_>, IntrospectError> {
+        imports_of(self.handle)
     }
 
-    fn paramtype(self) -> u8 {
-        b'i'
+    pub fn exports(&self) -> Result<Exports<'_>, IntrospectError> {
+        exports_of(self.handle)
     }
 }
 
+pub unsafe trait Arg {}
+
+unsafe impl Arg for f32 {}
+unsafe impl Arg for f64 {}
+unsafe impl Arg for i32 {}
+unsafe impl Arg for i64 {}
+unsafe impl Arg for u32 {}
+unsafe impl Arg for u64 {}
+
+/// Something that can be bound into a child process: any function or closure that takes and
+/// returns `Arg`s. `Args` is only there to keep the impls apart.
+pub trait IntoBinding<Args> {
+    /// Returns the table index of a function that takes a pointer to the environment as its first
+    /// argument, followed by the real arguments, and the environment to give it.
+    fn into_binding(self) -> (u32, alloc::boxed::Box<dyn core::any::Any>);
+}
+
+macro_rules! impl_into_binding {
+    ( $( $arg:ident : $ty:ident ),* ) => {
+        impl<F, $( $ty: Arg, )* R: Arg> IntoBinding<($( $ty, )* R,)> for F
+        where
+            F: Fn($( $ty ),*) -> R + 'static,
+        {
+            fn into_binding(self) -> (u32, alloc::boxed::Box<dyn core::any::Any>) {
+                extern "C" fn call<F: Fn($( $ty ),*) -> R, $( $ty, )* R>(
+                    env: *const F,
+                    $( $arg: $ty ),*
+                ) -> R {
+                    unsafe { (*env)($( $arg ),*) }
+                }
+
+                (
+                    call::<F, $( $ty, )* R> as *const () as usize as u32,
+                    alloc::boxed::Box::new(self),
+                )
+            }
+        }
+
+        impl<F, $( $ty: Arg ),*> IntoBinding<($( $ty, )* (),)> for F
+        where
+            F: Fn($( $ty ),*) + 'static,
+        {
+            fn into_binding(self) -> (u32, alloc::boxed::Box<dyn core::any::Any>) {
+                extern "C" fn call<F: Fn($( $ty ),*), $( $ty ),*>(
+                    env: *const F,
+                    $( $arg: $ty ),*
+                ) {
+                    unsafe { (*env)($( $arg ),*) }
+                }
+
+                (
+                    call::<F, $( $ty ),*> as *const () as usize as u32,
+                    alloc::boxed::Box::new(self),
+                )
+            }
+        }
+    };
+}
+
+impl_into_binding!();
+impl_into_binding!(a: T1);
+impl_into_binding!(a: T1, b: T2);
+impl_into_binding!(a: T1, b: T2, c: T3);
+impl_into_binding!(a: T1, b: T2, c: T3, d: T4);
+
 #[derive(Debug)]
-pub enum InvokeError {}
+pub enum BindProcessError {
+    NameTooLong,
+    /// The host doesn't know about this process (it might have been spawned already).
+    InvalidHandle,
+    /// The host didn't think the name was valid UTF-8.
+    InvalidName,
+    /// The function isn't in our function table.
+    InvalidFunction,
+    /// The host doesn't provide a service by that name.
+    NoSuchService,
+    /// The process we tried to bind to doesn't export anything by that name.
+    NoSuchExport,
+    /// The process we tried to bind to exports that name, but it's not a function.
+    NotAFunction,
+    Unknown(u32),
+}
 
-impl ProcessHandle {
-    pub fn invoke(&mut self, fn_name: &str, params: Params) -> Result<u64, InvokeError> {
-        let mut result: core::mem::MaybeUninit<u64> = core::mem::MaybeUninit::uninit();
+impl BindProcessError {
+    fn from_code(code: u32) -> Self {
+        match ErrorCode::from_u32(code) {
+            Some(ErrorCode::InvalidHandle) => BindProcessError::InvalidHandle,
+            Some(ErrorCode::InvalidUtf8) => BindProcessError::InvalidName,
+            Some(ErrorCode::InvalidFunction) => BindProcessError::InvalidFunction,
+            Some(ErrorCode::NoSuchService) => BindProcessError::NoSuchService,
+            Some(ErrorCode::NoSuchExport) => BindProcessError::NoSuchExport,
+            Some(ErrorCode::NotAFunction) => BindProcessError::NotAFunction,
+            _ => BindProcessError::Unknown(code),
+        }
+    }
+}
+
+impl CreateProcessHandle {
+    pub fn bind<Args>(
+        &mut self,
+        name: &str,
+        to: impl IntoBinding<Args>,
+    ) -> Result<(), BindProcessError> {
+        let (func, env) = to.into_binding();
 
+        let result;
         unsafe {
-            _invoke(
-                self.0,
-                fn_name.as_ptr(),
-                fn_name.len().try_into().expect("function name too long"),
-                params.0.as_ptr(),
-                params.1.as_ptr(),
-                params.0.len() as u32,
-                result.as_mut_ptr(),
+            result = _bind_env(
+                self.handle,
+                name.as_ptr(),
+                name.len()
+                    .try_into()
+                    .map_err(|_| BindProcessError::NameTooLong)?,
+                func as *const u8,
+                &*env as *const dyn core::any::Any as *const u8,
             );
+        }
 
-            Ok(result.assume_init())
+        if result == 0 {
+            self.envs.push(env);
+            Ok(())
+        } else {
+            Err(BindProcessError::from_code(result))
         }
     }
-}
 
-#[panic_handler]
-fn panic_handler(_panic: &core::panic::PanicInfo) -> ! {
+    /// Binds `name` to a service the host provides, like `"log"` or `"clock"`.
+    pub fn bind_service(&mut self, name: &str, service: &str) -> Result<(), BindProcessError> {
+        let result;
+        unsafe {
+            result = _bind_service(
+                self.handle,
+                name.as_ptr(),
+                name.len()
+                    .try_into()
+                    .map_err(|_| BindProcessError::NameTooLong)?,
+                service.as_ptr(),
+                service
+                    .len()
+                    .try_into()
+                    .map_err(|_| BindProcessError::NameTooLong)?,
+            );
+        }
+
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(BindProcessError::from_code(result))
+        }
+    }
+
+    /// Binds the memory import `name` to a shared memory. Writes to it from the child show up for
+    /// everything else it's bound to, and the other way around.
+    pub fn bind_memory(
+        &mut self,
+        name: &str,
+        memory: &SharedMemory,
+    ) -> Result<(), BindProcessError> {
+        let result;
+        unsafe {
+            result = _bind_memory(
+                self.handle,
+                name.as_ptr(),
+                name.len()
+                    .try_into()
+                    .map_err(|_| BindProcessError::NameTooLong)?,
+                memory.handle,
+            );
+        }
+
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(BindProcessError::from_code(result))
+        }
+    }
+
+    /// Binds `name` to the function `target` exports as `export`. If `target` gets killed, calls
+    /// to it will trap.
+    pub fn bind_export(
+        &mut self,
+        name: &str,
+        target: &ProcessHandle,
+        export: &str,
+    ) -> Result<(), BindProcessError> {
+        let result;
+        unsafe {
+            result = _bind_export(
+                self.handle,
+                name.as_ptr(),
+                name.len()
+                    .try_into()
+                    .map_err(|_| BindProcessError::NameTooLong)?,
+                target.handle,
+                export.as_ptr(),
+                export
+                    .len()
+                    .try_into()
+                    .map_err(|_| BindProcessError::NameTooLong)?,
+            );
+        }
+
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(BindProcessError::from_code(result))
+        }
+    }
+}
+
+pub struct ProcessHandle {
+    handle: u32,
+    envs: Envs,
+}
+
+impl Drop for ProcessHandle {
+    fn drop(&mut self) {
+        let result = unsafe { _kill(self.handle) };
+
+        if result != 0 {
+            // It's still alive, and might still call the closures.
+            core::mem::forget(core::mem::take(&mut self.envs));
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum KillError {
+    InvalidHandle,
+    /// The process (or something it spawned) is running right now, further up the call stack.
+    Busy,
+    Unknown(u32),
+}
+
+impl KillError {
+    fn from_code(code: u32) -> Self {
+        match ErrorCode::from_u32(code) {
+            Some(ErrorCode::InvalidHandle) => KillError::InvalidHandle,
+            Some(ErrorCode::ProcessBusy) => KillError::Busy,
+            _ => KillError::Unknown(code),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SetFuelError {
+    InvalidHandle,
+    Unknown(u32),
+}
+
+impl SetFuelError {
+    fn from_code(code: u32) -> Self {
+        match ErrorCode::from_u32(code) {
+            Some(ErrorCode::InvalidHandle) => SetFuelError::InvalidHandle,
+            _ => SetFuelError::Unknown(code),
+        }
+    }
+}
+
+impl ProcessHandle {
+    /// Limits how many more instructions the process can run, or takes the limit away with
+    /// `None`.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) -> Result<(), SetFuelError> {
+        let fuel = match fuel {
+            Some(fuel) => fuel.min(i64::MAX as u64) as i64,
+            None => -1,
+        };
+
+        let result = unsafe { _set_fuel(self.handle, fuel) };
+
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(SetFuelError::from_code(result))
+        }
+    }
+
+    /// Kills the process. Dropping the handle does the same thing, but ignores errors.
+    pub fn kill(mut self) -> Result<(), KillError> {
+        let handle = self.handle;
+        let envs = core::mem::take(&mut self.envs);
+        core::mem::forget(self);
+
+        let result = unsafe { _kill(handle) };
+
+        if result == 0 {
+            Ok(())
+        } else {
+            // It's still alive, and might still call the closures.
+            core::mem::forget(envs);
+            Err(KillError::from_code(result))
+        }
+    }
+}
+
+/// What a process can use. See `CreateProcessHandle::set_limits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// How far any memory it has can grow, in 64KiB pages.
+    pub memory_pages: u32,
+    /// How far its table can grow.
+    pub table_elements: u32,
+    /// The biggest module it can `create`, in bytes.
+    pub module_size: u32,
+    /// How many calls into it (by `invoke`, or through something it exports being bound) can be in
+    /// progress at once, nested inside each other.
+    pub call_depth: u32,
+    /// How many values its own code can have on its stack at once, counting locals.
+    pub value_stack: u32,
+}
+
+#[derive(Debug)]
+pub enum SetLimitsError {
+    InvalidHandle,
+    /// Tried to give it more than we have.
+    LimitExceeded,
+    Unknown(u32),
+}
+
+impl SetLimitsError {
+    fn from_code(code: u32) -> Self {
+        match ErrorCode::from_u32(code) {
+            Some(ErrorCode::InvalidHandle) => SetLimitsError::InvalidHandle,
+            Some(ErrorCode::LimitExceeded) => SetLimitsError::LimitExceeded,
+            _ => SetLimitsError::Unknown(code),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SpawnError {
+    InvalidHandle,
+    /// The module imports something that wasn't bound, and the host doesn't provide.
+    MissingImport,
+    /// The module couldn't be instantiated with what was bound, most likely because a bound
+    /// function has the wrong signature, or a bound memory is too small.
+    InstantiationFailed,
+    /// The module wants a bigger memory or table than the host will give it.
+    TooLarge,
+    /// The module wants a bigger memory or table than its limits allow, or a shared memory bound
+    /// to it could grow past them.
+    LimitExceeded,
+    /// The module's start function went over its stack limits, or we're nested too deep to run it.
+    StackOverflow,
+    Unknown(u32),
+}
+
+impl SpawnError {
+    fn from_code(code: u32) -> Self {
+        match ErrorCode::from_u32(code) {
+            Some(ErrorCode::InvalidHandle) => SpawnError::InvalidHandle,
+            Some(ErrorCode::MissingImport) => SpawnError::MissingImport,
+            Some(ErrorCode::TooLarge) => SpawnError::TooLarge,
+            Some(ErrorCode::LimitExceeded) => SpawnError::LimitExceeded,
+            Some(ErrorCode::StackOverflow) => SpawnError::StackOverflow,
+            Some(ErrorCode::InstantiationFailed) => SpawnError::InstantiationFailed,
+            _ => SpawnError::Unknown(code),
+        }
+    }
+}
+
+impl CreateProcessHandle {
+    /// Limits what the process can use once it's spawned. It starts out with the same limits we
+    /// have, and can't be given more.
+    pub fn set_limits(&mut self, limits: Limits) -> Result<(), SetLimitsError> {
+        let result = unsafe {
+            _set_limits(
+                self.handle,
+                limits.memory_pages,
+                limits.table_elements,
+                limits.module_size,
+                limits.call_depth,
+                limits.value_stack,
+            )
+        };
+
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(SetLimitsError::from_code(result))
+        }
+    }
+
+    pub fn spawn(mut self) -> Result<ProcessHandle, SpawnError> {
+        let mut err_code: u32 = 0;
+        let new_handle;
+
+        // The host takes the handle whether or not this works, so there's nothing to close.
+        let handle = self.handle;
+        let envs = core::mem::take(&mut self.envs);
+        core::mem::forget(self);
+
+        unsafe {
+            new_handle = _spawn(handle, &mut err_code as *mut u32);
+        }
+
+        if err_code == 0 {
+            Ok(ProcessHandle {
+                handle: new_handle,
+                envs,
+            })
+        } else {
+            Err(SpawnError::from_code(err_code))
+        }
+    }
+}
+
+/// Logs a message for the host to record, if it's interested in messages at this level.
+pub fn log(level: wasmabi::LogLevel, message: &str) {
+    pragma_str(level as u32, message);
+}
+
+/// Tells the host this would be a good place to stop, if it's being debugged.
+pub fn breakpoint() {
+    unsafe {
+        _pragma(wasmabi::PRAGMA_BREAKPOINT, core::ptr::null());
+    }
+}
+
+/// Marks this point in the host's log with a name.
+pub fn marker(name: &str) {
+    pragma_str(wasmabi::PRAGMA_MARKER, name);
+}
+
+fn pragma_str(code: u32, s: &str) {
+    let pair = [s.as_ptr() as u32, s.len() as u32];
+
+    unsafe {
+        _pragma(code, pair.as_ptr() as *const u8);
+    }
+}
+
+/// The host's stdout, as used by `print!` and `println!`.
+pub struct Stdout;
+
+/// The host's stderr, as used by `eprint!` and `eprintln!`.
+pub struct Stderr;
+
+fn write_fd(fd: u32, s: &str) -> core::fmt::Result {
+    match unsafe { _write(fd, s.as_ptr(), s.len() as u32) } {
+        0 => Ok(()),
+        _ => Err(core::fmt::Error),
+    }
+}
+
+impl core::fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write_fd(wasmabi::STDOUT, s)
+    }
+}
+
+impl core::fmt::Write for Stderr {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write_fd(wasmabi::STDERR, s)
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    let _ = core::fmt::Write::write_fmt(&mut Stdout, args);
+}
+
+#[doc(hidden)]
+pub fn _eprint(args: core::fmt::Arguments) {
+    let _ = core::fmt::Write::write_fmt(&mut Stderr, args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ( $( $arg:tt )* ) => {
+        $crate::_print(format_args!($( $arg )*))
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
+    ( $( $arg:tt )* ) => {
+        $crate::print!("{}\n", format_args!($( $arg )*))
+    };
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ( $( $arg:tt )* ) => {
+        $crate::_eprint(format_args!($( $arg )*))
+    };
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => {
+        $crate::eprint!("\n")
+    };
+    ( $( $arg:tt )* ) => {
+        $crate::eprint!("{}\n", format_args!($( $arg )*))
+    };
+}
+
+#[macro_export]
+macro_rules! params {
+    ( $( $x:expr ),* ) => {
+        {
+            let mut temp_vec = alloc::vec::Vec::new();
+            let mut type_vec = alloc::vec::Vec::new();
+            $(
+                {
+                    use $crate::IntoParam;
+                    temp_vec.push($x.into_param());
+                    type_vec.push($x.paramtype());
+                }
+            )*
+            unsafe {
+                $crate::Params::new(temp_vec, type_vec)
+            }
+        }
+    };
+}
+
+pub struct Params(alloc::vec::Vec<u64>, alloc::vec::Vec<u8>);
+
+impl Params {
+    pub unsafe fn new(values: alloc::vec::Vec<u64>, types: alloc::vec::Vec<u8>) -> Self {
+        Self(values, types)
+    }
+
+    pub fn empty() -> Self {
+        Self(alloc::vec::Vec::new(), alloc::vec::Vec::new())
+    }
+
+    pub fn push<T: IntoParam + Copy>(&mut self, param: T) {
+        self.0.push(param.into_param());
+        self.1.push(param.paramtype());
+    }
+}
+
+pub trait IntoParam {
+    fn into_param(self) -> u64;
+    fn paramtype(self) -> u8;
+}
+
+/// Something that turns into a whole list of parameters, like a struct with
+/// `#[derive(IntoParams)]`.
+pub trait IntoParams {
+    fn into_params(self) -> Params;
+}
+
+macro_rules! impl_into_param {
+    ( $( $ty:ty => $tag:ident, |$v:ident| $bits:expr; )* ) => {
+        $(
+            impl IntoParam for $ty {
+                fn into_param(self) -> u64 {
+                    let $v = self;
+                    $bits
+                }
+
+                fn paramtype(self) -> u8 {
+                    wasmabi::$tag
+                }
+            }
+        )*
+    };
+}
+
+impl_into_param! {
+    i32 => TYPE_I32, |v| v as u32 as u64;
+    u32 => TYPE_I32, |v| v as u64;
+    i64 => TYPE_I64, |v| v as u64;
+    u64 => TYPE_I64, |v| v;
+    f32 => TYPE_F32, |v| v.to_bits() as u64;
+    f64 => TYPE_F64, |v| v.to_bits();
+    bool => TYPE_I32, |v| v as u64;
+    char => TYPE_I32, |v| v as u64;
+    // Pointers are 32 bits on wasm32
+    usize => TYPE_I32, |v| v as u32 as u64;
+    isize => TYPE_I32, |v| v as i32 as u32 as u64;
+}
+
+// Buffers only go in as a pointer and a length, so they need to still be around when the function
+// is invoked.
+impl IntoParam for &[u8] {
+    fn into_param(self) -> u64 {
+        self.as_ptr() as u32 as u64 | (self.len() as u64) << 32
+    }
+
+    fn paramtype(self) -> u8 {
+        wasmabi::TYPE_BUFFER
+    }
+}
+
+impl IntoParam for &str {
+    fn into_param(self) -> u64 {
+        self.as_bytes().into_param()
+    }
+
+    fn paramtype(self) -> u8 {
+        wasmabi::TYPE_BUFFER
+    }
+}
+
+/// Handed out to the host when something passes us a buffer. The function it's passed to owns it,
+/// and can get it back with `take_buffer`.
+#[no_mangle]
+pub extern "C" fn __wasmos_alloc(len: u32) -> *mut u8 {
+    let mut buf = alloc::vec::Vec::<u8>::with_capacity(len as usize);
+    let ptr = buf.as_mut_ptr();
+    core::mem::forget(buf);
+    ptr
+}
+
+/// Called by whoever invoked us once they've copied out a buffer we returned with
+/// `return_buffer`.
+///
+/// # Safety
+///
+/// `ptr` and `len` must have come from `return_buffer`, and not been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn __wasmos_free(ptr: *mut u8, len: u32) {
+    drop(take_buffer(ptr, len));
+}
+
+/// Takes ownership of a buffer passed to an exported function.
+///
+/// # Safety
+///
+/// `ptr` and `len` must be the (pointer, length) pair the function was passed for a buffer
+/// argument, and can only be taken once.
+pub unsafe fn take_buffer(ptr: *mut u8, len: u32) -> alloc::vec::Vec<u8> {
+    alloc::vec::Vec::from_raw_parts(ptr, len as usize, len as usize)
+}
+
+/// Turns a buffer into something an exported function can return (as a u64), for the invoker to
+/// get with `invoke_buffer`.
+pub fn return_buffer(buf: alloc::vec::Vec<u8>) -> u64 {
+    let len = buf.len() as u64;
+    let ptr = alloc::boxed::Box::into_raw(buf.into_boxed_slice()) as *mut u8 as u32 as u64;
+    ptr | len << 32
+}
+
+#[derive(Debug)]
+pub enum InvokeError {
+    NameTooLong,
+    InvalidHandle,
+    /// The host didn't think the name was valid UTF-8.
+    InvalidName,
+    /// The process doesn't export anything by that name.
+    NoSuchExport,
+    /// The process exports something by that name, but it's not a function.
+    NotAFunction,
+    /// We passed a different number of parameters than the function takes. Buffers count as
+    /// two i32s.
+    ArityMismatch {
+        expected: alloc::vec::Vec<ValueType>,
+        provided: alloc::vec::Vec<ValueType>,
+    },
+    /// The parameters' types don't match the function's signature. Buffers count as two i32s.
+    TypeMismatch {
+        expected: alloc::vec::Vec<ValueType>,
+        provided: alloc::vec::Vec<ValueType>,
+    },
+    /// A buffer went outside of our memory or the process's.
+    OutOfBounds,
+    /// The process couldn't allocate space for a buffer we passed it (it might not export an
+    /// allocator).
+    AllocationFailed,
+    /// The process ran out of fuel before the function returned.
+    OutOfFuel,
+    /// We waited on a job from inside the function it's running, or on a call that has to wait
+    /// for one further up the stack to finish first.
+    ProcessBusy,
+    /// Calls were nested deeper than the process (or the host) allows, or the function went
+    /// over its value stack limit.
+    StackOverflow,
+    /// The function panicked, or called into something that did.
+    Panicked {
+        /// What it said, with anything that isn't valid UTF-8 replaced.
+        message: alloc::string::String,
+    },
+    /// The function hit an `unreachable` instruction.
+    Unreachable,
+    /// The function accessed its memory or a table out of bounds, or called an empty table entry.
+    AccessOutOfBounds,
+    /// The function divided by zero, or took a remainder of it.
+    DivisionByZero,
+    /// The function overflowed a signed division, or truncated a float that doesn't fit (or is
+    /// NaN) to an integer.
+    IntegerOverflow,
+    /// The function made an indirect call to a function of a different type than it expected.
+    IndirectCallMismatch,
+    /// The function trapped in a host function for some other reason, or the host couldn't run
+    /// it at all.
+    HostError,
+    /// The function returned something other than what we asked for. `None` if it didn't return
+    /// anything.
+    ResultMismatch {
+        returned: Option<ValueType>,
+    },
+    Unknown(u32),
+}
+
+impl InvokeError {
+    fn from_code(code: u32) -> Self {
+        match ErrorCode::from_u32(code) {
+            Some(ErrorCode::InvalidHandle) => InvokeError::InvalidHandle,
+            Some(ErrorCode::InvalidUtf8) => InvokeError::InvalidName,
+            Some(ErrorCode::NoSuchExport) => InvokeError::NoSuchExport,
+            Some(ErrorCode::NotAFunction) => InvokeError::NotAFunction,
+            Some(ErrorCode::OutOfBounds) => InvokeError::OutOfBounds,
+            Some(ErrorCode::AllocationFailed) => InvokeError::AllocationFailed,
+            Some(ErrorCode::OutOfFuel) => InvokeError::OutOfFuel,
+            Some(ErrorCode::ProcessBusy) => InvokeError::ProcessBusy,
+            Some(ErrorCode::StackOverflow) => InvokeError::StackOverflow,
+            Some(ErrorCode::Unreachable) => InvokeError::Unreachable,
+            Some(ErrorCode::AccessOutOfBounds) => InvokeError::AccessOutOfBounds,
+            Some(ErrorCode::DivisionByZero) => InvokeError::DivisionByZero,
+            Some(ErrorCode::IntegerOverflow) => InvokeError::IntegerOverflow,
+            Some(ErrorCode::IndirectCallMismatch) => InvokeError::IndirectCallMismatch,
+            Some(ErrorCode::HostError) => InvokeError::HostError,
+            _ => InvokeError::Unknown(code),
+        }
+    }
+
+    /// The host wrote `len` bytes of what the function said into `message`, or as many as fit.
+    fn panicked(message: &[u8], len: u64) -> Self {
+        let len = (len as usize).min(message.len());
+
+        InvokeError::Panicked {
+            message: alloc::string::String::from_utf8_lossy(&message[..len]).into_owned(),
+        }
+    }
+}
+
+impl ProcessHandle {
+    pub fn invoke<R: FromResult>(
+        &mut self,
+        fn_name: &str,
+        params: Params,
+    ) -> Result<R, InvokeError> {
+        let mut result: u64 = 0;
+        let mut result_type: u8 = wasmabi::TYPE_NONE;
+        let mut message = [0; wasmabi::MAX_PANIC_MESSAGE as usize];
+
+        unsafe {
+            let err_code = _invoke(
+                self.handle,
+                fn_name.as_ptr(),
+                fn_name
+                    .len()
+                    .try_into()
+                    .map_err(|_| InvokeError::NameTooLong)?,
+                params.0.as_ptr(),
+                params.1.as_ptr(),
+                params.0.len() as u32,
+                &mut result as *mut u64,
+                &mut result_type as *mut u8,
+                message.as_mut_ptr(),
+                message.len() as u32,
+            );
+
+            if ErrorCode::from_u32(err_code) == Some(ErrorCode::Panicked) {
+                return Err(InvokeError::panicked(&message, result));
+            }
+
+            if err_code != 0 {
+                return Err(self.call_error(err_code, fn_name, &params));
+            }
+        }
+
+        returned(result_type, result)
+    }
+
+    /// Starts invoking a function, without waiting for it to finish. The arguments get checked
+    /// straight away, what it returns gets checked once it's done. Calls into the same process
+    /// run one after the other, in the order they were made.
+    pub fn invoke_async<R: FromResult>(
+        &mut self,
+        fn_name: &str,
+        params: Params,
+    ) -> Result<Job<R>, InvokeError> {
+        let mut err_code: u32 = 0;
+
+        let handle = unsafe {
+            _invoke_async(
+                self.handle,
+                fn_name.as_ptr(),
+                fn_name
+                    .len()
+                    .try_into()
+                    .map_err(|_| InvokeError::NameTooLong)?,
+                params.0.as_ptr(),
+                params.1.as_ptr(),
+                params.0.len() as u32,
+                &mut err_code as *mut u32,
+            )
+        };
+
+        if err_code != 0 {
+            return Err(self.call_error(err_code, fn_name, &params));
+        }
+
+        Ok(Job {
+            handle,
+            done: false,
+            _result: core::marker::PhantomData,
+        })
+    }
+
+    fn call_error(&self, code: u32, fn_name: &str, params: &Params) -> InvokeError {
+        match ErrorCode::from_u32(code) {
+            Some(ErrorCode::ArityMismatch) | Some(ErrorCode::TypeMismatch) => {
+                self.signature_mismatch(code, fn_name, &params.1)
+            }
+            _ => InvokeError::from_code(code),
+        }
+    }
+
+    pub fn imports(&self) -> Result<Imports<'_>, IntrospectError> {
+        imports_of(self.handle)
+    }
+
+    pub fn exports(&self) -> Result<Exports<'_>, IntrospectError> {
+        exports_of(self.handle)
+    }
+
+    /// The host only tells us *that* the arguments were wrong, so go and find out what it wanted.
+    fn signature_mismatch(&self, code: u32, fn_name: &str, types: &[u8]) -> InvokeError {
+        let expected = self
+            .exports()
+            .ok()
+            .and_then(|mut exports| exports.find(|export| export.name == fn_name))
+            .and_then(|export| match export.ty {
+                ItemType::Function { params, .. } => Some(params),
+                _ => None,
+            })
+            .unwrap_or_default();
+
+        let mut provided = alloc::vec::Vec::new();
+        for &tag in types {
+            match tag {
+                wasmabi::TYPE_BUFFER => provided.extend_from_slice(&[ValueType::I32; 2]),
+                tag => provided.extend(ValueType::from_tag(tag)),
+            }
+        }
+
+        if ErrorCode::from_u32(code) == Some(ErrorCode::ArityMismatch) {
+            InvokeError::ArityMismatch { expected, provided }
+        } else {
+            InvokeError::TypeMismatch { expected, provided }
+        }
+    }
+
+    /// Invokes a function that returns a buffer (as a pointer and length packed into a u64, see
+    /// `return_buffer`), and copies it out. It's given back to the process afterwards if it
+    /// exports a way to free it.
+    pub fn invoke_buffer(
+        &mut self,
+        fn_name: &str,
+        params: Params,
+    ) -> Result<alloc::vec::Vec<u8>, InvokeError> {
+        let packed: u64 = self.invoke(fn_name, params)?;
+        let ptr = packed as u32;
+        let len = (packed >> 32) as u32;
+
+        let mut buf = alloc::vec![0; len as usize];
+
+        let err_code =
+            unsafe { _read_memory(self.handle, ptr as *const u8, len, buf.as_mut_ptr()) };
+
+        if err_code != 0 {
+            return Err(InvokeError::from_code(err_code));
+        }
+
+        match self.invoke::<()>(wasmabi::FREE_EXPORT, params!(ptr, len)) {
+            Ok(_) | Err(InvokeError::NoSuchExport) => Ok(buf),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Checks what a function returned is what we asked for.
+fn returned<R: FromResult>(result_type: u8, result: u64) -> Result<R, InvokeError> {
+    let value = Value::from_raw(result_type, result);
+    R::from_result(value).ok_or(InvokeError::ResultMismatch {
+        returned: value.map(|v| v.ty()),
+    })
+}
+
+/// A function call that's been started with `ProcessHandle::invoke_async`. Dropping it before
+/// it's finished cancels it.
+///
+/// It's a `Future` too, but nothing ever wakes it up, so whatever polls it will just keep
+/// polling. Each poll gives the scheduler a turn.
+pub struct Job<R> {
+    handle: u32,
+    /// Once it's done the host forgets about the handle.
+    done: bool,
+    _result: core::marker::PhantomData<fn() -> R>,
+}
+
+impl<R: FromResult> Job<R> {
+    /// Lets the scheduler run a bit, then returns the result if it's finished.
+    pub fn try_wait(&mut self) -> Option<Result<R, InvokeError>> {
+        if self.done {
+            return Some(Err(InvokeError::InvalidHandle));
+        }
+
+        let mut result: u64 = 0;
+        let mut result_type: u8 = wasmabi::TYPE_NONE;
+        let mut message = [0; wasmabi::MAX_PANIC_MESSAGE as usize];
+
+        let err_code = unsafe {
+            _poll(
+                self.handle,
+                &mut result as *mut u64,
+                &mut result_type as *mut u8,
+                message.as_mut_ptr(),
+                message.len() as u32,
+            )
+        };
+
+        if ErrorCode::from_u32(err_code) == Some(ErrorCode::Pending) {
+            return None;
+        }
+
+        self.done = true;
+
+        if ErrorCode::from_u32(err_code) == Some(ErrorCode::Panicked) {
+            return Some(Err(InvokeError::panicked(&message, result)));
+        }
+
+        if err_code != 0 {
+            return Some(Err(InvokeError::from_code(err_code)));
+        }
+
+        Some(returned(result_type, result))
+    }
+
+    /// Runs everything that's queued until this is finished, then returns the result.
+    pub fn wait(mut self) -> Result<R, InvokeError> {
+        let mut result: u64 = 0;
+        let mut result_type: u8 = wasmabi::TYPE_NONE;
+        let mut message = [0; wasmabi::MAX_PANIC_MESSAGE as usize];
+
+        let err_code = unsafe {
+            _wait(
+                self.handle,
+                &mut result as *mut u64,
+                &mut result_type as *mut u8,
+                message.as_mut_ptr(),
+                message.len() as u32,
+            )
+        };
+
+        // Unless we're waiting from inside it, in which case it's still there.
+        if ErrorCode::from_u32(err_code) != Some(ErrorCode::ProcessBusy) {
+            self.done = true;
+        }
+
+        if ErrorCode::from_u32(err_code) == Some(ErrorCode::Panicked) {
+            return Err(InvokeError::panicked(&message, result));
+        }
+
+        if err_code != 0 {
+            return Err(InvokeError::from_code(err_code));
+        }
+
+        returned(result_type, result)
+    }
+}
+
+impl<R: FromResult> core::future::Future for Job<R> {
+    type Output = Result<R, InvokeError>;
+
+    fn poll(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Self::Output> {
+        match self.get_mut().try_wait() {
+            Some(result) => core::task::Poll::Ready(result),
+            None => {
+                cx.waker().wake_by_ref();
+                core::task::Poll::Pending
+            }
+        }
+    }
+}
+
+impl<R> Drop for Job<R> {
+    fn drop(&mut self) {
+        if !self.done {
+            unsafe {
+                _cancel(self.handle);
+            }
+        }
+    }
+}
+
+/// Creates a channel that can hold up to `capacity` messages that haven't been received yet.
+pub fn channel(capacity: u32) -> (Sender, Receiver) {
+    let mut sender = 0;
+    let mut receiver = 0;
+
+    let err_code = unsafe { _channel_create(capacity, &mut sender, &mut receiver) };
+    assert!(err_code == 0, "couldn't create a channel: {}", err_code);
+
+    (Sender { handle: sender }, Receiver { handle: receiver })
+}
+
+/// The end of a channel, as it gets sent over other channels. Whoever gets it has to know which
+/// end it is, using the wrong one just fails.
+#[derive(Debug)]
+pub struct Handle {
+    handle: u32,
+}
+
+impl Handle {
+    /// # Safety
+    ///
+    /// The handle must be the end of a channel that we own, and nothing else can close it.
+    pub unsafe fn from_raw(handle: u32) -> Self {
+        Handle { handle }
+    }
+
+    pub fn into_sender(self) -> Sender {
+        Sender {
+            handle: self.into_raw(),
+        }
+    }
+
+    pub fn into_receiver(self) -> Receiver {
+        Receiver {
+            handle: self.into_raw(),
+        }
+    }
+
+    fn into_raw(self) -> u32 {
+        let handle = self.handle;
+        core::mem::forget(self);
+        handle
+    }
+
+    /// Gives this to a process we spawned, and returns the handle for it to use.
+    pub fn give(self, process: &ProcessHandle) -> Result<u32, GiveError> {
+        let err_code = unsafe { _give(self.handle, process.handle) };
+
+        if err_code != 0 {
+            return Err(GiveError::from_code(err_code));
+        }
+
+        Ok(self.into_raw())
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe {
+            _close(self.handle);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Sender {
+    handle: u32,
+}
+
+impl Sender {
+    pub fn into_handle(self) -> Handle {
+        let handle = self.handle;
+        core::mem::forget(self);
+        Handle { handle }
+    }
+
+    pub fn try_send(&mut self, bytes: &[u8]) -> Result<(), SendError> {
+        self.try_send_with(bytes, alloc::vec::Vec::new())
+    }
+
+    /// Sends the ends of some other channels along with the message. If it doesn't work, they get
+    /// closed.
+    pub fn try_send_with(
+        &mut self,
+        bytes: &[u8],
+        handles: alloc::vec::Vec<Handle>,
+    ) -> Result<(), SendError> {
+        let raw: alloc::vec::Vec<u32> = handles.iter().map(|handle| handle.handle).collect();
+
+        let err_code = unsafe {
+            _send(
+                self.handle,
+                bytes.as_ptr(),
+                bytes.len() as u32,
+                raw.as_ptr(),
+                raw.len() as u32,
+            )
+        };
+
+        if err_code != 0 {
+            return Err(SendError::from_code(err_code));
+        }
+
+        // They're the receiver's now.
+        for handle in handles {
+            handle.into_raw();
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        unsafe {
+            _close(self.handle);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Receiver {
+    handle: u32,
+}
+
+#[derive(Debug)]
+pub struct Message {
+    pub bytes: alloc::vec::Vec<u8>,
+    pub handles: alloc::vec::Vec<Handle>,
+}
+
+impl Receiver {
+    pub fn into_handle(self) -> Handle {
+        let handle = self.handle;
+        core::mem::forget(self);
+        Handle { handle }
+    }
+
+    pub fn try_recv(&mut self) -> Result<Message, RecvError> {
+        let mut bytes = alloc::vec::Vec::new();
+        let mut handles = alloc::vec::Vec::new();
+
+        loop {
+            let mut len: u32 = 0;
+            let mut handles_len: u32 = 0;
+
+            let err_code = unsafe {
+                _recv(
+                    self.handle,
+                    bytes.as_mut_ptr(),
+                    bytes.len() as u32,
+                    &mut len,
+                    handles.as_mut_ptr(),
+                    handles.len() as u32,
+                    &mut handles_len,
+                )
+            };
+
+            match ErrorCode::from_u32(err_code) {
+                // Now we know how much room it needs.
+                Some(ErrorCode::TooLarge) => {
+                    bytes.resize(len as usize, 0);
+                    handles.resize(handles_len as usize, 0);
+                }
+                _ if err_code != 0 => return Err(RecvError::from_code(err_code)),
+                _ => {
+                    bytes.truncate(len as usize);
+                    handles.truncate(handles_len as usize);
+
+                    return Ok(Message {
+                        bytes,
+                        handles: handles
+                            .into_iter()
+                            .map(|handle| Handle { handle })
+                            .collect(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        unsafe {
+            _close(self.handle);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    /// It's not ours, or it's a receiver.
+    InvalidHandle,
+    /// The channel's already holding as many messages as it can.
+    Full,
+    /// The receiver's been closed.
+    Closed,
+    OutOfBounds,
+    Unknown(u32),
+}
+
+impl SendError {
+    fn from_code(code: u32) -> Self {
+        match ErrorCode::from_u32(code) {
+            Some(ErrorCode::InvalidHandle) => SendError::InvalidHandle,
+            Some(ErrorCode::ChannelFull) => SendError::Full,
+            Some(ErrorCode::ChannelClosed) => SendError::Closed,
+            Some(ErrorCode::OutOfBounds) => SendError::OutOfBounds,
+            _ => SendError::Unknown(code),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RecvError {
+    /// It's not ours, or it's a sender.
+    InvalidHandle,
+    /// Nothing's been sent yet.
+    Empty,
+    /// Nothing's left, and the sender's been closed so nothing ever will be.
+    Closed,
+    Unknown(u32),
+}
+
+impl RecvError {
+    fn from_code(code: u32) -> Self {
+        match ErrorCode::from_u32(code) {
+            Some(ErrorCode::InvalidHandle) => RecvError::InvalidHandle,
+            Some(ErrorCode::ChannelEmpty) => RecvError::Empty,
+            Some(ErrorCode::ChannelClosed) => RecvError::Closed,
+            _ => RecvError::Unknown(code),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GiveError {
+    InvalidHandle,
+    Unknown(u32),
+}
+
+impl GiveError {
+    fn from_code(code: u32) -> Self {
+        match ErrorCode::from_u32(code) {
+            Some(ErrorCode::InvalidHandle) => GiveError::InvalidHandle,
+            _ => GiveError::Unknown(code),
+        }
+    }
+}
+
+/// Memory that can be bound to child processes with `CreateProcessHandle::bind_memory`. We can't
+/// map it into our own memory, so we have to copy in and out of it.
+#[derive(Debug)]
+pub struct SharedMemory {
+    handle: u32,
+}
+
+impl SharedMemory {
+    /// Starts out `pages` * 64KiB big, and can grow up to `max_pages`, or as far as our limits
+    /// allow if that's less (or there isn't one).
+    pub fn new(pages: u32, max_pages: Option<u32>) -> Result<Self, MemoryError> {
+        let mut err_code: u32 = 0;
+
+        let handle =
+            unsafe { _memory_create(pages, max_pages.map_or(-1, |max| max as i32), &mut err_code) };
+
+        if err_code != 0 {
+            return Err(MemoryError::from_code(err_code));
+        }
+
+        Ok(SharedMemory { handle })
+    }
+
+    pub fn read(&self, offset: u32, buf: &mut [u8]) -> Result<(), MemoryError> {
+        let err_code =
+            unsafe { _memory_read(self.handle, offset, buf.as_mut_ptr(), buf.len() as u32) };
+
+        if err_code != 0 {
+            return Err(MemoryError::from_code(err_code));
+        }
+
+        Ok(())
+    }
+
+    pub fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), MemoryError> {
+        let err_code =
+            unsafe { _memory_write(self.handle, offset, bytes.as_ptr(), bytes.len() as u32) };
+
+        if err_code != 0 {
+            return Err(MemoryError::from_code(err_code));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        unsafe {
+            _close(self.handle);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MemoryError {
+    InvalidHandle,
+    /// It couldn't be allocated, or the maximum is smaller than what it starts with.
+    AllocationFailed,
+    /// It starts out bigger than our limits allow.
+    LimitExceeded,
+    /// We went past the end of it.
+    OutOfBounds,
+    Unknown(u32),
+}
+
+impl MemoryError {
+    fn from_code(code: u32) -> Self {
+        match ErrorCode::from_u32(code) {
+            Some(ErrorCode::InvalidHandle) => MemoryError::InvalidHandle,
+            Some(ErrorCode::AllocationFailed) => MemoryError::AllocationFailed,
+            Some(ErrorCode::LimitExceeded) => MemoryError::LimitExceeded,
+            Some(ErrorCode::OutOfBounds) => MemoryError::OutOfBounds,
+            _ => MemoryError::Unknown(code),
+        }
+    }
+}
+
+/// Somewhere to format a panic message without allocating, since we might be panicking because
+/// allocating failed. It holds as much as the host keeps, and whatever doesn't fit gets cut off.
+struct PanicBuffer {
+    buf: [u8; wasmabi::MAX_PANIC_MESSAGE as usize],
+    len: usize,
+}
+
+impl core::fmt::Write for PanicBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic_handler(panic: &core::panic::PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    let mut message = PanicBuffer {
+        buf: [0; wasmabi::MAX_PANIC_MESSAGE as usize],
+        len: 0,
+    };
+    let _ = match panic.location() {
+        Some(location) => write!(message, "{} at {}", panic.message(), location),
+        None => write!(message, "{}", panic.message()),
+    };
+
     unsafe {
+        _panic(message.buf.as_ptr(), message.len as u32);
+        // In case the host lets us carry on anyway.
         core::arch::wasm32::unreachable();
     }
 }
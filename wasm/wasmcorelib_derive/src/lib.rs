@@ -0,0 +1,56 @@
+// Derives for wasmcorelib. Use them through wasmcorelib's re-exports, the generated code refers to
+// things in there.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields};
+
+/// Flattens a struct into `Params`, one parameter per field, in order. Every field has to be
+/// `IntoParam`.
+#[proc_macro_derive(IntoParams)]
+pub fn derive_into_params(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return syn::Error::new_spanned(name, "IntoParams can only be derived for structs")
+                .to_compile_error()
+                .into()
+        }
+    };
+
+    let accessors: Vec<_> = match fields {
+        Fields::Named(fields) => fields
+            .named
+            .iter()
+            .map(|field| {
+                let ident = &field.ident;
+                quote!(#ident)
+            })
+            .collect(),
+        Fields::Unnamed(fields) => (0..fields.unnamed.len())
+            .map(|i| {
+                let index = syn::Index::from(i);
+                quote!(#index)
+            })
+            .collect(),
+        Fields::Unit => Vec::new(),
+    };
+
+    let expanded = quote! {
+        impl #impl_generics ::wasmcorelib::IntoParams for #name #ty_generics #where_clause {
+            fn into_params(self) -> ::wasmcorelib::Params {
+                let mut params = ::wasmcorelib::Params::empty();
+                #( params.push(self.#accessors); )*
+                params
+            }
+        }
+    };
+
+    expanded.into()
+}